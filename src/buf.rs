@@ -1,24 +1,525 @@
 use std::{
     collections::VecDeque,
     io::{self, Write},
-    sync::Mutex,
+    ops::Range,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
 };
 
 use crossterm::{
-    cursor, queue,
+    cursor,
+    event::{self, DisableMouseCapture, EnableMouseCapture, KeyCode, KeyEvent, KeyModifiers},
+    queue,
+    style::{self, Attribute},
     terminal::{self, ClearType},
 };
 
-const MESSAGE_BUF: u16 = 20;
+use unicode_width::UnicodeWidthChar;
+
+use crate::prelude::*;
+
+/// Size used when the terminal can't tell its own
+const DEFAULT_SIZE: (u16, u16) = (80, 25);
+/// Lines scrolled by one step of the mouse wheel
+const WHEEL_LINES: usize = 3;
+/// Most rows taken by the input of a multi-line message, the rest of it scrolls
+const INPUT_ROWS_MAX: u16 = 5;
+/// Texts kept by an editor to bring back with Up and Down
+const EDITOR_HISTORY_MAX: usize = 100;
+/// Columns between tab stops
+const TAB_WIDTH: usize = 8;
+/// Columns of the member list, right of message area
+const SIDEBAR_WIDTH: u16 = 20;
+/// Narrower terminals show no member list
+const SIDEBAR_MIN_SCREEN: u16 = 60;
+
+/// A line of message area.
+struct Line {
+    clock: u64,
+    time: u64,
+    /// Uid of the message shown, to show it again when it changes
+    uid: Option<u64>,
+    text: String,
+}
+
+/// Text searched in message area, see `search`.
+struct Search {
+    query: String,
+    /// Index in `MESSAGES` of the line shown, with its place among the lines found and their count
+    current: Option<(usize, usize, usize)>,
+}
+
+/// Rows of the chat screen, from the size of the terminal.
+#[derive(Clone, Copy, Debug)]
+pub struct Layout {
+    pub width: u16,
+    pub height: u16,
+    /// Rows of the input, one per line of the message being typed
+    pub input_rows: u16,
+}
+
+impl Layout {
+    /// Columns of the member list with its border, `0` when the terminal is too narrow for it.
+    pub fn sidebar_width(&self) -> u16 {
+        if self.width >= SIDEBAR_MIN_SCREEN {
+            SIDEBAR_WIDTH
+        } else {
+            0
+        }
+    }
+
+    /// Columns of message area, left of the member list.
+    pub fn message_width(&self) -> u16 {
+        self.width - self.sidebar_width()
+    }
+
+    /// Rows of message area, at the top of the screen.
+    pub fn message_rows(&self) -> u16 {
+        self.height.saturating_sub(2 + self.input_rows).max(1)
+    }
+
+    /// Row under message area, telling how far it is scrolled up.
+    pub fn scroll_line(&self) -> u16 {
+        self.message_rows()
+    }
+
+    pub fn input_line(&self) -> u16 {
+        self.message_rows() + 1
+    }
+
+    /// Last row, the status bar.
+    pub fn info_line(&self) -> u16 {
+        self.input_line() + self.input_rows
+    }
+}
+
+/// What the status bar shows, besides the unread lines.
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct Status {
+    /// Name of the room
+    pub room: String,
+    /// False while trying to reach room owner again
+    pub connected: bool,
+    /// Round trip of the last heartbeat answered by room owner
+    pub latency: Option<Duration>,
+    /// Shown at the end, like our address and the key of the room
+    pub info: String,
+}
+
+/// How a text is shown. Colors come from the theme, see `config::Theme`.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Tone {
+    /// Joins, leaves and the end of the room, in bold
+    System,
+    Error,
+    Success,
+    /// Messages replayed by room owner
+    History,
+    /// Notes like `(edited)`
+    Muted,
+    /// Quote of the message replied to, in italic
+    Quote,
+    Private,
+    Memo,
+    /// Our id in a message of someone else, in bold
+    Mention,
+    /// Bold, without a color
+    Strong,
+}
+
+/// Style the text for the screen, or leave it plain when colors are off.
+pub fn paint(text: &str, tone: Tone) -> String {
+    let theme = config::get().theme;
+    if !colors_enabled(&theme) {
+        return text.to_string();
+    }
+    let styled = style::style(text);
+    let styled = match tone {
+        Tone::System => styled.with(theme.system).attribute(Attribute::Bold),
+        Tone::Error => styled.with(theme.error),
+        Tone::Success => styled.with(theme.success),
+        Tone::History => styled.with(theme.history),
+        Tone::Muted => styled.with(theme.muted),
+        Tone::Quote => styled.with(theme.muted).attribute(Attribute::Italic),
+        Tone::Private => styled.with(theme.private),
+        Tone::Memo => styled.with(theme.memo),
+        Tone::Mention => styled.with(theme.mention).attribute(Attribute::Bold),
+        Tone::Strong => styled.attribute(Attribute::Bold),
+    };
+    format!("{}", styled)
+}
+
+/// The id in its own color, the same on every member since it only depends on the id.
+pub fn paint_id(id: &str) -> String {
+    let theme = config::get().theme;
+    if !colors_enabled(&theme) || theme.names.is_empty() {
+        return id.to_string();
+    }
+    // FNV-1a, so the color doesn't change with the version of Rust
+    let hash = id.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    let color = theme.names[(hash % theme.names.len() as u64) as usize];
+    format!("{}", style::style(id).with(color))
+}
+
+fn colors_enabled(theme: &config::Theme) -> bool {
+    match theme.colors {
+        config::ColorMode::Always => true,
+        config::ColorMode::Never => false,
+        config::ColorMode::Auto => {
+            std::env::var_os("NO_COLOR").is_none()
+                && std::env::var("TERM").map_or(true, |term| term != "dumb")
+        }
+    }
+}
+
+/// Lines added below message area while it is scrolled up
+static UNREAD: AtomicUsize = AtomicUsize::new(0);
 
 lazy_static! {
-    static ref MESSAGES: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+    static ref STATUS: Mutex<Status> = Mutex::new(Status::default());
+    /// Rows of the member list and status bar last drawn, so they are drawn only when they change
+    static ref SIDEBAR_DRAWN: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    static ref STATUS_DRAWN: Mutex<String> = Mutex::new(String::new());
+    /// Lines in message area, ordered by `(clock, time)`
+    static ref MESSAGES: Mutex<VecDeque<Line>> = Mutex::new(VecDeque::new());
+    /// Rows message area is scrolled up from the last one
+    static ref SCROLL: Mutex<usize> = Mutex::new(0);
+    static ref SEARCH: Mutex<Option<Search>> = Mutex::new(None);
+    static ref LAYOUT: Mutex<Layout> = Mutex::new(Layout {
+        width: DEFAULT_SIZE.0,
+        height: DEFAULT_SIZE.1,
+        input_rows: 1,
+    });
 }
 
 pub fn initialize() {
+    let (width, height) = terminal::size().unwrap_or(DEFAULT_SIZE);
+    resize(width, height);
     enter_alternate_screen();
 }
 
+/// Take a new size of the terminal, the caller draws the screen again.
+pub fn resize(width: u16, height: u16) {
+    {
+        let mut lock_layout = LAYOUT.lock().unwrap();
+        lock_layout.width = width;
+        lock_layout.height = height;
+    }
+    clamp_scroll();
+}
+
+/// Give the input as many rows as the lines of the message being typed, up to `INPUT_ROWS_MAX`.
+/// Returns true if the layout changed, then the caller draws the screen again.
+pub fn set_input_rows(rows: u16) -> bool {
+    let rows = rows.clamp(1, INPUT_ROWS_MAX);
+    {
+        let mut lock_layout = LAYOUT.lock().unwrap();
+        if lock_layout.input_rows == rows {
+            return false;
+        }
+        lock_layout.input_rows = rows;
+    }
+    clamp_scroll();
+    true
+}
+
+fn clamp_scroll() {
+    let max = max_scroll();
+    let mut lock_scroll = SCROLL.lock().unwrap();
+    *lock_scroll = (*lock_scroll).min(max);
+}
+
+pub fn layout() -> Layout {
+    *LAYOUT.lock().unwrap()
+}
+
+pub fn input_line() -> u16 {
+    layout().input_line()
+}
+
+pub fn info_line() -> u16 {
+    layout().info_line()
+}
+
+/// Scroll message area up by a page, older lines come into view.
+pub fn page_up() {
+    scroll_up(layout().message_rows().saturating_sub(1).max(1) as usize);
+}
+
+/// Scroll message area down by a page, back to the last line at most.
+pub fn page_down() {
+    scroll_down(layout().message_rows().saturating_sub(1).max(1) as usize);
+}
+
+pub fn wheel_up() {
+    scroll_up(WHEEL_LINES);
+}
+
+pub fn wheel_down() {
+    scroll_down(WHEEL_LINES);
+}
+
+fn scroll_up(lines: usize) {
+    let max = max_scroll();
+    let mut lock_scroll = SCROLL.lock().unwrap();
+    *lock_scroll = (*lock_scroll + lines).min(max);
+}
+
+fn scroll_down(lines: usize) {
+    let mut lock_scroll = SCROLL.lock().unwrap();
+    *lock_scroll = lock_scroll.saturating_sub(lines);
+    if *lock_scroll == 0 {
+        UNREAD.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Most rows message area can be scrolled up: until the first row is at the top.
+fn max_scroll() -> usize {
+    let layout = layout();
+    let lock_messages = MESSAGES.lock().unwrap();
+    let total: usize = lock_messages
+        .iter()
+        .map(|line| wrap(&line.text, layout.message_width() as usize).len())
+        .sum();
+    total.saturating_sub(layout.message_rows() as usize)
+}
+
+/// Look for the text in message area, case ignored, and scroll to the last line that has it.
+/// Returns how many lines have it, the search ends at once when there are none.
+pub fn search(query: &str) -> usize {
+    *SEARCH.lock().unwrap() = Some(Search {
+        query: query.to_string(),
+        current: None,
+    });
+    match search_next(true) {
+        Some((_, count)) => count,
+        None => {
+            end_search();
+            0
+        }
+    }
+}
+
+/// Scroll to the next line found, older or newer than the one shown. It stops at the first and the
+/// last. Returns the place of the line among the lines found, oldest first, and their count.
+pub fn search_next(older: bool) -> Option<(usize, usize)> {
+    let (query, current) = match SEARCH.lock().unwrap().as_ref() {
+        Some(search) => (search.query.clone(), search.current),
+        None => return None,
+    };
+    let found: Vec<usize> = MESSAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !find_all(&line.text, &query).is_empty())
+        .map(|(index, _)| index)
+        .collect();
+    let last = found.len().checked_sub(1)?;
+    let place = match current.and_then(|(index, ..)| found.iter().position(|i| *i == index)) {
+        Some(place) if older => place.saturating_sub(1),
+        Some(place) => (place + 1).min(last),
+        None => last,
+    };
+    scroll_to(found[place]);
+    if let Some(search) = SEARCH.lock().unwrap().as_mut() {
+        search.current = Some((found[place], place + 1, found.len()));
+    }
+    Some((place + 1, found.len()))
+}
+
+pub fn end_search() {
+    *SEARCH.lock().unwrap() = None;
+}
+
+pub fn searching() -> bool {
+    SEARCH.lock().unwrap().is_some()
+}
+
+/// Scroll so the line is in the middle of message area, or as near as it can be.
+fn scroll_to(index: usize) {
+    let layout = layout();
+    let max = max_scroll();
+    let rows_after: usize = MESSAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .skip(index + 1)
+        .map(|line| wrap(&line.text, layout.message_width() as usize).len())
+        .sum();
+    let scroll = rows_after.saturating_sub(layout.message_rows() as usize / 2);
+    *SCROLL.lock().unwrap() = scroll.min(max);
+}
+
+/// Byte ranges of the query in the text shown, case ignored. Style escapes are left out.
+fn find_all(text: &str, query: &str) -> Vec<Range<usize>> {
+    let query: Vec<char> = query.chars().flat_map(char::to_lowercase).collect();
+    if query.is_empty() {
+        return Vec::new();
+    }
+    // Chars shown, lower case, with the bytes of the text they come from
+    let mut shown: Vec<(char, Range<usize>)> = Vec::new();
+    let mut chars = text.char_indices();
+    while let Some((index, c)) = chars.next() {
+        if c == '\u{1b}' {
+            for (_, c) in chars.by_ref() {
+                if ('\u{40}'..='\u{7e}').contains(&c) && c != '[' {
+                    break;
+                }
+            }
+            continue;
+        }
+        for lower in c.to_lowercase() {
+            shown.push((lower, index..index + c.len_utf8()));
+        }
+    }
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start + query.len() <= shown.len() {
+        let window = &shown[start..start + query.len()];
+        if window.iter().map(|(c, _)| *c).eq(query.iter().copied()) {
+            ranges.push(window[0].1.start..window[query.len() - 1].1.end);
+            start += query.len();
+        } else {
+            start += 1;
+        }
+    }
+    ranges
+}
+
+/// The text with the query in reverse video. Only reverse is turned off after it, so the colors
+/// around it stay.
+fn highlight(text: &str, query: &str) -> String {
+    let ranges = find_all(text, query);
+    if ranges.is_empty() || !colors_enabled(&config::get().theme) {
+        return text.to_string();
+    }
+    let mut shown = String::new();
+    let mut last = 0;
+    for range in ranges {
+        shown.push_str(&text[last..range.start]);
+        shown.push_str("\u{1b}[7m");
+        shown.push_str(&text[range.clone()]);
+        shown.push_str("\u{1b}[27m");
+        last = range.end;
+    }
+    shown.push_str(&text[last..]);
+    shown
+}
+
+/// Columns the text takes on screen: wide chars like CJK take two, style escapes none.
+pub fn display_width(text: &str) -> usize {
+    wrap(text, usize::MAX)
+        .iter()
+        .map(|row| {
+            let mut width = 0;
+            for_each_char(row, |c| width += c.width().unwrap_or(0));
+            width
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// First row of the text cut to `width` columns.
+pub fn truncate(text: &str, width: usize) -> String {
+    wrap(text, width).swap_remove(0)
+}
+
+/// Rows of at most `width` columns showing the text, one or more per line of it. Rows are cut
+/// between chars, so a wide char never ends up split. Styles still on at the end of a row are
+/// turned on again at the start of the next one, and tabs become spaces.
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(2);
+    let mut rows = vec![String::new()];
+    let mut column = 0;
+    // Style escapes since the last reset
+    let mut styles = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let row = rows.last_mut().unwrap();
+        if c == '\u{1b}' {
+            let mut escape = String::from(c);
+            if chars.peek() == Some(&'[') {
+                for c in chars.by_ref() {
+                    escape.push(c);
+                    if escape.len() > 2 && ('\u{40}'..='\u{7e}').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            row.push_str(&escape);
+            if is_reset(&escape) {
+                styles.clear();
+            } else if escape.ends_with('m') {
+                styles.push_str(&escape);
+            }
+            continue;
+        }
+        if c == '\n' {
+            end_row(&mut rows, &styles);
+            column = 0;
+            continue;
+        }
+        let (c, count) = match c {
+            '\t' => (' ', TAB_WIDTH - column % TAB_WIDTH),
+            c if c.is_control() => continue,
+            c => (c, 1),
+        };
+        let char_width = c.width().unwrap_or(0);
+        for _ in 0..count {
+            if column + char_width > width {
+                end_row(&mut rows, &styles);
+                column = 0;
+            }
+            rows.last_mut().unwrap().push(c);
+            column += char_width;
+        }
+    }
+    rows
+}
+
+/// The escape turns styles off: a full reset, or a reset of the color or of an attribute.
+fn is_reset(escape: &str) -> bool {
+    match escape
+        .strip_prefix("\u{1b}[")
+        .and_then(|e| e.strip_suffix('m'))
+    {
+        Some(params) => params.split(';').all(|param| {
+            param.is_empty() || matches!(param.parse::<u8>(), Ok(0 | 22..=29 | 39 | 49))
+        }),
+        None => false,
+    }
+}
+
+/// Close the last row and start the next one with the styles still on.
+fn end_row(rows: &mut Vec<String>, styles: &str) {
+    if !styles.is_empty() {
+        rows.last_mut().unwrap().push_str("\u{1b}[0m");
+    }
+    rows.push(styles.to_string());
+}
+
+/// Call `f` on every char of the text shown on screen, leaving out style escapes.
+fn for_each_char<F: FnMut(char)>(text: &str, mut f: F) {
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            for c in chars.by_ref() {
+                if ('\u{40}'..='\u{7e}').contains(&c) && c != '[' {
+                    break;
+                }
+            }
+        } else {
+            f(c);
+        }
+    }
+}
+
 pub fn reset() {
     clear_buf();
     leave_alternate_screen();
@@ -27,53 +528,217 @@ pub fn reset() {
 pub fn clear_buf() {
     let mut lock_messages = MESSAGES.lock().unwrap();
     lock_messages.clear();
+    *SCROLL.lock().unwrap() = 0;
+    UNREAD.store(0, Ordering::SeqCst);
 }
 
+/// Long lines are cut at the edge of the terminal instead of running into the next row.
 pub fn enter_alternate_screen() {
     let mut stdout = io::stdout();
-    queue!(stdout, terminal::EnterAlternateScreen,).unwrap();
+    queue!(
+        stdout,
+        terminal::EnterAlternateScreen,
+        terminal::DisableLineWrap,
+        EnableMouseCapture,
+    )
+    .unwrap();
     stdout.flush().unwrap();
 }
 
 pub fn leave_alternate_screen() {
     let mut stdout = io::stdout();
-    queue!(stdout, terminal::LeaveAlternateScreen,).unwrap();
+    queue!(
+        stdout,
+        DisableMouseCapture,
+        terminal::EnableLineWrap,
+        terminal::LeaveAlternateScreen,
+    )
+    .unwrap();
     stdout.flush().unwrap();
 }
 
+/// Draw message area, scrolled as chosen by the user, and how many rows are below it, with the
+/// member list and the status bar. The cursor stays where it was, in the input.
 pub fn print_message() {
+    let layout = layout();
+    let rows = layout.message_rows() as usize;
     let mut stdout = io::stdout();
+    let search = SEARCH
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|search| (search.query.clone(), search.current));
+    let lock_messages = MESSAGES.lock().unwrap();
+    let scroll = *SCROLL.lock().unwrap();
+    let texts: Vec<String> = lock_messages
+        .iter()
+        .flat_map(|line| match &search {
+            Some((query, _)) => wrap(
+                &highlight(&line.text, query),
+                layout.message_width() as usize,
+            ),
+            None => wrap(&line.text, layout.message_width() as usize),
+        })
+        .collect();
+    let end = texts.len() - scroll.min(texts.len());
+    let start = end.saturating_sub(rows);
+
+    queue!(stdout, cursor::SavePosition).unwrap();
+    for row in 0..rows {
+        queue!(
+            stdout,
+            cursor::MoveTo(0, row as u16),
+            terminal::Clear(ClearType::CurrentLine),
+        )
+        .unwrap();
+        if start + row < end {
+            print!("{}", texts[start + row]);
+        }
+    }
     queue!(
         stdout,
-        cursor::MoveTo(0, MESSAGE_BUF),
-        terminal::Clear(ClearType::FromCursorUp),
+        cursor::MoveTo(0, layout.scroll_line()),
         terminal::Clear(ClearType::CurrentLine),
     )
     .unwrap();
-    queue!(stdout, cursor::MoveTo(0, 0)).unwrap();
+    let note = match search {
+        Some((_, Some((_, place, count)))) => Some(format!(
+            "-- {} of {} found, n older, N newer, Esc to stop --",
+            place, count
+        )),
+        _ if scroll > 0 => Some(format!(
+            "-- {} more lines below, PageDown to scroll --",
+            scroll
+        )),
+        _ => None,
+    };
+    if let Some(note) = note {
+        print!(
+            "{}",
+            paint(&truncate(&note, layout.width as usize), Tone::Muted)
+        );
+    }
+    queue!(stdout, cursor::RestorePosition).unwrap();
+    drop(lock_messages);
+    SIDEBAR_DRAWN.lock().unwrap().clear();
+    STATUS_DRAWN.lock().unwrap().clear();
+    print_sidebar();
+    print_status();
 
-    let lock_messages = MESSAGES.lock().unwrap();
+    stdout.flush().unwrap();
+}
 
-    for message in lock_messages.iter() {
-        println!("{}", message);
+/// Draw the member list right of message area, if it changed since it was last drawn. Room owner
+/// is marked with `@`, idle and away members are dimmed.
+pub fn print_sidebar() {
+    let layout = layout();
+    if layout.sidebar_width() == 0 {
+        return;
+    }
+    let width = layout.sidebar_width() as usize - 2;
+    let members = roster::members();
+    let mut rows = vec![truncate(&format!("Members ({})", members.len()), width)];
+    for member in members.iter() {
+        let marker = if member.owner { "@" } else { " " };
+        let name = truncate(&member.id, width - 1);
+        if member.is_away() {
+            let away = truncate(&format!("{}{} away", marker, name), width);
+            rows.push(paint(&away, Tone::Muted));
+        } else if member.is_idle() {
+            let idle = truncate(&format!("{}{} idle", marker, name), width);
+            rows.push(paint(&idle, Tone::Muted));
+        } else {
+            rows.push(format!("{}{}", marker, paint_id(&name)));
+        }
     }
+    rows.truncate(layout.message_rows() as usize);
 
+    let mut lock_drawn = SIDEBAR_DRAWN.lock().unwrap();
+    if *lock_drawn == rows {
+        return;
+    }
+    let mut stdout = io::stdout();
+    queue!(stdout, cursor::SavePosition).unwrap();
+    for row in 0..layout.message_rows() {
+        queue!(
+            stdout,
+            cursor::MoveTo(layout.message_width(), row),
+            terminal::Clear(ClearType::UntilNewLine),
+        )
+        .unwrap();
+        print!(
+            "{} {}",
+            paint("│", Tone::Muted),
+            rows.get(row as usize).map_or("", String::as_str)
+        );
+    }
+    queue!(stdout, cursor::RestorePosition).unwrap();
     stdout.flush().unwrap();
+    *lock_drawn = rows;
+}
+
+pub fn set_status(status: Status) {
+    *STATUS.lock().unwrap() = status;
 }
 
-pub fn println(s: &String, line: u16) {
+/// Draw the status bar on the last row, if it changed since it was last drawn.
+pub fn print_status() {
+    let layout = layout();
+    let status = STATUS.lock().unwrap().clone();
+    let mut parts = vec![status.room.clone()];
+    parts.push(if status.connected {
+        paint("connected", Tone::Success)
+    } else {
+        paint("reconnecting", Tone::Error)
+    });
+    if let Some(latency) = status.latency {
+        parts.push(format!("{} ms", latency.as_millis()));
+    }
+    let unread = UNREAD.load(Ordering::SeqCst);
+    if unread > 0 {
+        parts.push(paint(&format!("{} unread", unread), Tone::Strong));
+    }
+    let typing = match roster::typing().as_slice() {
+        [] => None,
+        [id] => Some(format!("{} is typing…", id)),
+        [first, second] => Some(format!("{} and {} are typing…", first, second)),
+        ids => Some(format!("{} people are typing…", ids.len())),
+    };
+    if let Some(typing) = typing {
+        parts.push(paint(&typing, Tone::Muted));
+    }
+    parts.push(status.info.clone());
+    let line = truncate(
+        &parts
+            .iter()
+            .filter(|part| !part.is_empty())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(" | "),
+        layout.width as usize,
+    );
+
+    let mut lock_drawn = STATUS_DRAWN.lock().unwrap();
+    if *lock_drawn == line {
+        return;
+    }
     let mut stdout = io::stdout();
     queue!(
         stdout,
-        cursor::MoveTo(0, line),
+        cursor::SavePosition,
+        cursor::MoveTo(0, layout.info_line()),
         terminal::Clear(ClearType::CurrentLine),
     )
     .unwrap();
-    println!("{}", s);
+    print!("{}", line);
+    queue!(stdout, cursor::RestorePosition).unwrap();
     stdout.flush().unwrap();
+    *lock_drawn = line;
 }
 
-pub fn print_input(head: &String, input: &String, line: u16) {
+/// Print on the row `line`, cut to the width of the terminal. Without a new line, so the last row
+/// doesn't scroll the screen.
+pub fn println(s: &str, line: u16) {
     let mut stdout = io::stdout();
     queue!(
         stdout,
@@ -81,17 +746,446 @@ pub fn print_input(head: &String, input: &String, line: u16) {
         terminal::Clear(ClearType::CurrentLine),
     )
     .unwrap();
+    print!("{}", truncate(s, layout().width as usize));
+    stdout.flush().unwrap();
+}
+
+pub fn print_input(head: &str, input: &str, line: u16) {
+    println(&format!("{}{}", head, input), line);
+}
 
-    println!("{}{}", head, input);
+/// Draw `head` and the text of the editor from `line` on at most `rows` rows, and put the cursor
+/// where the editor is. Rows that don't fit scroll with the cursor.
+pub fn print_editor(head: &str, editor: &Editor, line: u16, rows: u16) {
+    let mut stdout = io::stdout();
+    let (texts, (cursor_row, cursor_column)) = editor_rows(head, editor);
+    let rows = rows.max(1) as usize;
+    let first = (cursor_row + 1).saturating_sub(rows);
+
+    for row in 0..rows {
+        queue!(
+            stdout,
+            cursor::MoveTo(0, line + row as u16),
+            terminal::Clear(ClearType::CurrentLine),
+        )
+        .unwrap();
+        if let Some(text) = texts.get(first + row) {
+            print!("{}", text);
+        }
+    }
+    queue!(
+        stdout,
+        cursor::MoveTo(cursor_column as u16, line + (cursor_row - first) as u16),
+        cursor::Show,
+    )
+    .unwrap();
     stdout.flush().unwrap();
 }
 
-pub fn push_message(message: &String) {
+/// Rows `print_editor` needs to show all of the text.
+pub fn editor_height(head: &str, editor: &Editor) -> u16 {
+    editor_rows(head, editor).0.len() as u16
+}
+
+/// Rows showing `head` and the text of the editor, wrapped to the width of the terminal, with the
+/// row and column of the cursor. Lines after the first are indented under `head`.
+fn editor_rows(head: &str, editor: &Editor) -> (Vec<String>, (usize, usize)) {
+    let width = (layout().width as usize).max(2);
+    let head = truncate(head, width / 2);
+    let indent = " ".repeat(display_width(&head));
+    let mut rows = vec![head];
+    let mut column = indent.len();
+    let mut cursor = None;
+    for (index, c) in editor.text.iter().enumerate() {
+        let char_width = match c {
+            '\n' => 0,
+            c => c.width().unwrap_or(0),
+        };
+        if column + char_width > width {
+            rows.push(String::new());
+            column = 0;
+        }
+        if index == editor.cursor {
+            cursor = Some((rows.len() - 1, column));
+        }
+        if *c == '\n' {
+            rows.push(indent.clone());
+            column = indent.len();
+        } else {
+            rows.last_mut().unwrap().push(*c);
+            column += char_width;
+        }
+    }
+    let cursor = cursor.unwrap_or_else(|| {
+        if column >= width {
+            rows.push(String::new());
+            column = 0;
+        }
+        (rows.len() - 1, column)
+    });
+    (rows, cursor)
+}
+
+/// What a key did to an editor.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Edit {
+    Unchanged,
+    Changed,
+    /// Enter was pressed, with the text typed. The editor is empty again.
+    Submit(String),
+}
+
+/// Input of a line of text: moves by char and word, deletes, history with Up and Down, and
+/// multi-line text with Shift+Enter or Alt+Enter. A paste is typed as it is, new lines included.
+pub struct Editor {
+    text: Vec<char>,
+    /// Index in `text` of the char after the cursor
+    cursor: usize,
+    /// Texts submitted, oldest first
+    history: Vec<String>,
+    /// Index in `history` of the text shown, `None` when editing a new one
+    browsing: Option<usize>,
+    /// Text being typed before going through history
+    draft: Vec<char>,
+    /// Most bytes of the text
+    max_len: usize,
+    multi_line: bool,
+    /// Chars that can be typed
+    accept: fn(char) -> bool,
+}
+
+impl Editor {
+    pub fn new(max_len: usize, multi_line: bool, accept: fn(char) -> bool) -> Editor {
+        Editor {
+            text: Vec::new(),
+            cursor: 0,
+            history: Vec::new(),
+            browsing: None,
+            draft: Vec::new(),
+            max_len,
+            multi_line,
+            accept,
+        }
+    }
+
+    pub fn text(&self) -> String {
+        self.text.iter().collect()
+    }
+
+    /// Replace the text, with the cursor at its end.
+    pub fn set_text(&mut self, text: &str) {
+        self.text = text.chars().collect();
+        self.cursor = self.text.len();
+    }
+
+    pub fn clear(&mut self) {
+        self.set_text("");
+        self.browsing = None;
+    }
+
+    /// Lines of the text, for the rows it takes.
+    pub fn line_count(&self) -> usize {
+        self.text.iter().filter(|c| **c == '\n').count() + 1
+    }
+
+    /// Line and column of the cursor, in chars.
+    pub fn cursor_position(&self) -> (usize, usize) {
+        let before = &self.text[..self.cursor];
+        let line = before.iter().filter(|c| **c == '\n').count();
+        let column = match before.iter().rposition(|c| *c == '\n') {
+            Some(start) => self.cursor - start - 1,
+            None => self.cursor,
+        };
+        (line, column)
+    }
+
+    /// Word before the cursor, back to a space, and whether it starts the text.
+    pub fn word_before_cursor(&self) -> (String, bool) {
+        let start = self.word_start();
+        (self.text[start..self.cursor].iter().collect(), start == 0)
+    }
+
+    /// Put `word` in place of the word before the cursor, to complete it.
+    pub fn replace_word_before_cursor(&mut self, word: &str) {
+        let start = self.word_start();
+        let len: usize = self.text[..start]
+            .iter()
+            .chain(self.text[self.cursor..].iter())
+            .map(|c| c.len_utf8())
+            .sum();
+        if len + word.len() <= self.max_len {
+            self.text.splice(start..self.cursor, word.chars());
+            self.cursor = start + word.chars().count();
+        }
+    }
+
+    pub fn handle(&mut self, key: KeyEvent) -> Edit {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+        let before = (self.text.clone(), self.cursor);
+        match key.code {
+            KeyCode::Enter if self.multi_line && (shift || alt || paste_pending()) => {
+                self.insert('\n');
+            }
+            KeyCode::Enter => {
+                let text = self.text();
+                if !text.trim().is_empty() && self.history.last() != Some(&text) {
+                    self.history.push(text.clone());
+                    if self.history.len() > EDITOR_HISTORY_MAX {
+                        self.history.remove(0);
+                    }
+                }
+                self.clear();
+                self.draft.clear();
+                return Edit::Submit(text);
+            }
+            KeyCode::Char('a') if control => self.cursor = self.line_start(),
+            KeyCode::Char('e') if control => self.cursor = self.line_end(),
+            KeyCode::Char('u') if control => {
+                let start = self.line_start();
+                self.text.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            KeyCode::Char('k') if control => {
+                let end = self.line_end();
+                self.text.drain(self.cursor..end);
+            }
+            KeyCode::Char('w') if control => self.delete_word_back(),
+            KeyCode::Char('d') if alt => self.delete_word_forward(),
+            KeyCode::Char('b') if alt => self.cursor = self.word_back(),
+            KeyCode::Char('f') if alt => self.cursor = self.word_forward(),
+            KeyCode::Char(c) if !control && !alt => self.insert(c),
+            KeyCode::Backspace if control || alt => self.delete_word_back(),
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.text.remove(self.cursor);
+            }
+            KeyCode::Delete if control || alt => self.delete_word_forward(),
+            KeyCode::Delete if self.cursor < self.text.len() => {
+                self.text.remove(self.cursor);
+            }
+            KeyCode::Left if control || alt => self.cursor = self.word_back(),
+            KeyCode::Right if control || alt => self.cursor = self.word_forward(),
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.text.len()),
+            KeyCode::Home => self.cursor = self.line_start(),
+            KeyCode::End => self.cursor = self.line_end(),
+            KeyCode::Up if self.cursor_position().0 > 0 => self.move_line(false),
+            KeyCode::Down if self.cursor_position().0 + 1 < self.line_count() => {
+                self.move_line(true)
+            }
+            KeyCode::Up => self.browse(false),
+            KeyCode::Down => self.browse(true),
+            _ => {}
+        }
+        if (self.text.clone(), self.cursor) == before {
+            Edit::Unchanged
+        } else {
+            Edit::Changed
+        }
+    }
+
+    fn insert(&mut self, c: char) {
+        let len: usize = self.text.iter().map(|c| c.len_utf8()).sum();
+        let allowed = if c == '\n' {
+            self.multi_line
+        } else {
+            !c.is_control() && (self.accept)(c)
+        };
+        if allowed && len + c.len_utf8() <= self.max_len {
+            self.text.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+
+    fn line_start(&self) -> usize {
+        self.text[..self.cursor]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |index| index + 1)
+    }
+
+    fn line_end(&self) -> usize {
+        self.text[self.cursor..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map_or(self.text.len(), |index| self.cursor + index)
+    }
+
+    /// Start of the text before the cursor that has no white space.
+    fn word_start(&self) -> usize {
+        self.text[..self.cursor]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .map_or(0, |index| index + 1)
+    }
+
+    /// Start of the word before the cursor.
+    fn word_back(&self) -> usize {
+        let mut index = self.cursor;
+        while index > 0 && !self.text[index - 1].is_alphanumeric() {
+            index -= 1;
+        }
+        while index > 0 && self.text[index - 1].is_alphanumeric() {
+            index -= 1;
+        }
+        index
+    }
+
+    /// End of the word after the cursor.
+    fn word_forward(&self) -> usize {
+        let mut index = self.cursor;
+        while index < self.text.len() && !self.text[index].is_alphanumeric() {
+            index += 1;
+        }
+        while index < self.text.len() && self.text[index].is_alphanumeric() {
+            index += 1;
+        }
+        index
+    }
+
+    fn delete_word_back(&mut self) {
+        let start = self.word_back();
+        self.text.drain(start..self.cursor);
+        self.cursor = start;
+    }
+
+    fn delete_word_forward(&mut self) {
+        let end = self.word_forward();
+        self.text.drain(self.cursor..end);
+    }
+
+    /// Move the cursor to the same column of the next or previous line, or its end if shorter.
+    fn move_line(&mut self, down: bool) {
+        let column = self.cursor_position().1;
+        let start = if down {
+            self.line_end() + 1
+        } else {
+            let mut start = self.line_start() - 1;
+            while start > 0 && self.text[start - 1] != '\n' {
+                start -= 1;
+            }
+            start
+        };
+        let end = self.text[start..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map_or(self.text.len(), |index| start + index);
+        self.cursor = (start + column).min(end);
+    }
+
+    /// Show the next or previous text of history. Going past the last one brings back the draft.
+    fn browse(&mut self, forward: bool) {
+        let index = match (self.browsing, forward) {
+            (None, false) if !self.history.is_empty() => {
+                self.draft = self.text.clone();
+                Some(self.history.len() - 1)
+            }
+            (Some(index), false) => Some(index.saturating_sub(1)),
+            (Some(index), true) if index + 1 < self.history.len() => Some(index + 1),
+            (Some(_), true) => None,
+            _ => return,
+        };
+        self.browsing = index;
+        self.text = match index {
+            Some(index) => self.history[index].chars().collect(),
+            None => self.draft.clone(),
+        };
+        self.cursor = self.text.len();
+    }
+}
+
+/// More keys are already waiting: the user is pasting, not typing.
+fn paste_pending() -> bool {
+    event::poll(Duration::from_millis(0)).unwrap_or(false)
+}
+
+/// Add a local line after everything seen so far.
+pub fn push_message(message: &str) {
+    insert_message(message, None, protocol::clock(), protocol::now_millis());
+}
+
+/// Add a line of a message at its place by Lamport clock, so late messages don't end up last.
+///
+/// When message area is scrolled up, it keeps showing the same lines.
+pub fn insert_message(message: &str, uid: Option<u64>, clock: u64, time: u64) {
+    let scrollback = config::get().scrollback;
+    let width = layout().message_width() as usize;
+    let row_count = |text: &str| wrap(text, width).len();
     let mut lock_message = MESSAGES.lock().unwrap();
-    if lock_message.len() > MESSAGE_BUF as usize {
+    let mut lock_scroll = SCROLL.lock().unwrap();
+    let index = lock_message
+        .iter()
+        .rposition(|line| (line.clock, line.time) <= (clock, time))
+        .map_or(0, |index| index + 1);
+    let line = Line {
+        clock,
+        time,
+        uid,
+        text: message.to_string(),
+    };
+    if *lock_scroll > 0 {
+        let rows_after: usize = lock_message
+            .iter()
+            .skip(index)
+            .map(|line| row_count(&line.text))
+            .sum();
+        if rows_after <= *lock_scroll {
+            *lock_scroll += row_count(&line.text);
+            UNREAD.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+    lock_message.insert(index, line);
+    if scrollback > 0 && lock_message.len() > scrollback {
         lock_message.pop_front();
+        let rows: usize = lock_message.iter().map(|line| row_count(&line.text)).sum();
+        *lock_scroll = (*lock_scroll).min(rows);
+    }
+}
+
+/// Add a line read from chat log before everything seen in the room, unless it is already there.
+/// Returns false if it was.
+pub fn insert_log_line(text: &str, time: u64) -> bool {
+    let shown = MESSAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|line| line.clock == 0 && line.time == time && line.text == text);
+    if !shown {
+        insert_message(text, None, 0, time);
     }
-    lock_message.push_back(message.clone());
+    !shown
+}
+
+/// Time of the oldest message in message area, lines from chat log left out.
+pub fn first_time() -> Option<u64> {
+    MESSAGES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|line| line.clock > 0)
+        .map(|line| line.time)
+        .min()
+}
+
+/// Change the line of the message `uid` in place. Returns false if it is no longer shown.
+pub fn replace_message(uid: u64, message: &str) -> bool {
+    let mut lock_message = MESSAGES.lock().unwrap();
+    match lock_message.iter_mut().find(|line| line.uid == Some(uid)) {
+        Some(line) => {
+            line.text = message.to_string();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Ring the terminal bell, the screen doesn't change.
+pub fn bell() {
+    let mut stdout = io::stdout();
+    print!("\x07");
+    stdout.flush().unwrap();
 }
 
 pub fn print_error<E: ToString>(err: &E) {
@@ -110,3 +1204,198 @@ pub fn clear_all() {
     )
     .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_all_ignores_case_and_styles() {
+        assert_eq!(
+            find_all("Hello hello HELLO", "hello"),
+            vec![0..5, 6..11, 12..17]
+        );
+        assert_eq!(find_all("hello", "HeLLo"), vec![0..5]);
+        assert!(find_all("hello", "").is_empty());
+        assert!(find_all("hell", "hello").is_empty());
+        // Escapes are not searched, the range still covers the text shown
+        assert_eq!(find_all("\u{1b}[31mHel\u{1b}[39mlo", "hello"), vec![5..15]);
+        assert!(find_all("\u{1b}[31m", "31m").is_empty());
+    }
+
+    #[test]
+    fn find_all_takes_matches_one_after_another() {
+        assert_eq!(find_all("aaaa", "aa"), vec![0..2, 2..4]);
+        assert_eq!(find_all("aaa", "aa"), vec![0..2]);
+        assert_eq!(find_all("abababa", "aba"), vec![0..3, 4..7]);
+    }
+
+    #[test]
+    fn find_all_gives_byte_ranges_of_multi_byte_text() {
+        assert_eq!(find_all("你好，世界", "世界"), vec![9..15]);
+        assert_eq!(find_all("ÉCOLE école", "école"), vec![0..6, 7..13]);
+        assert_eq!(find_all("hi 👋 hi", "👋"), vec![3..7]);
+        // `İ` lowers to two chars, the match covers the whole of it
+        assert_eq!(find_all("İstanbul", "i̇s"), vec![0..3]);
+    }
+
+    #[test]
+    fn search_moves_between_lines_found() {
+        clear_buf();
+        for (clock, line) in ["alpha", "beta", "\u{1b}[31mALPHA\u{1b}[39m", "alpha alpha"]
+            .iter()
+            .enumerate()
+        {
+            insert_message(line, None, clock as u64, 0);
+        }
+        assert_eq!(search("Alpha"), 3);
+        assert!(searching());
+        // The latest line comes first, then older ones, stopping at the ends
+        assert_eq!(search_next(true), Some((2, 3)));
+        assert_eq!(search_next(true), Some((1, 3)));
+        assert_eq!(search_next(true), Some((1, 3)));
+        assert_eq!(search_next(false), Some((2, 3)));
+        assert_eq!(search_next(false), Some((3, 3)));
+        assert_eq!(search_next(false), Some((3, 3)));
+        end_search();
+        assert_eq!(search_next(true), None);
+
+        assert_eq!(search("gamma"), 0);
+        assert!(!searching());
+        clear_buf();
+    }
+
+    #[test]
+    fn resets_are_read_as_numbers() {
+        for reset in [
+            "\u{1b}[m",
+            "\u{1b}[0m",
+            "\u{1b}[22m",
+            "\u{1b}[27m",
+            "\u{1b}[29;39;49m",
+        ] {
+            assert!(is_reset(reset), "{:?}", reset);
+        }
+        // Between "22" and "29" as strings, but styles that turn something on
+        for style in [
+            "\u{1b}[2m",
+            "\u{1b}[3m",
+            "\u{1b}[1m",
+            "\u{1b}[220m",
+            "\u{1b}[31m",
+        ] {
+            assert!(!is_reset(style), "{:?}", style);
+        }
+        assert!(!is_reset("\u{1b}[0;1m"));
+    }
+
+    #[test]
+    fn wide_chars_take_two_columns() {
+        assert_eq!(display_width(""), 0);
+        assert_eq!(display_width("hello"), 5);
+        assert_eq!(display_width("你好"), 4);
+        assert_eq!(display_width("👍 ok"), 5);
+        assert_eq!(display_width("\u{1b}[32mbob\u{1b}[0m"), 3);
+        // The widest line counts, a tab goes to the next stop
+        assert_eq!(display_width("a\nabc"), 3);
+        assert_eq!(display_width("ab\tc"), TAB_WIDTH + 1);
+    }
+
+    #[test]
+    fn wrap_cuts_between_chars() {
+        assert_eq!(wrap("abcdef", 4), vec!["abcd", "ef"]);
+        assert_eq!(wrap("ab\ncd", 4), vec!["ab", "cd"]);
+        // A wide char that doesn't fit goes to the next row whole
+        assert_eq!(wrap("abc你好", 4), vec!["abc", "你好"]);
+        assert_eq!(wrap("", 4), vec![""]);
+        for row in wrap("你好你好你好", 5) {
+            assert!(display_width(&row) <= 5, "{:?}", row);
+        }
+        assert_eq!(truncate("hello world", 5), "hello");
+    }
+
+    #[test]
+    fn wrap_carries_styles_to_the_next_row() {
+        let rows = wrap("\u{1b}[31mabcdef\u{1b}[0mgh", 4);
+        assert_eq!(
+            rows,
+            vec!["\u{1b}[31mabcd\u{1b}[0m", "\u{1b}[31mef\u{1b}[0mgh",]
+        );
+        // Nothing to carry after a reset
+        assert_eq!(wrap("\u{1b}[1mab\u{1b}[22mcdef", 4)[1], "ef");
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn typed(editor: &mut Editor, text: &str) {
+        for c in text.chars() {
+            editor.handle(key(KeyCode::Char(c)));
+        }
+    }
+
+    #[test]
+    fn editor_moves_and_deletes_by_word() {
+        let mut editor = Editor::new(100, false, |_| true);
+        typed(&mut editor, "hello big world");
+        assert_eq!(
+            editor.handle(KeyEvent::new(KeyCode::Left, KeyModifiers::CONTROL)),
+            Edit::Changed
+        );
+        assert_eq!(editor.cursor_position(), (0, 10));
+        editor.handle(KeyEvent::new(KeyCode::Char('w'), KeyModifiers::CONTROL));
+        assert_eq!(editor.text(), "hello world");
+        editor.handle(KeyEvent::new(KeyCode::Char('k'), KeyModifiers::CONTROL));
+        assert_eq!(editor.text(), "hello ");
+        editor.handle(key(KeyCode::Home));
+        assert_eq!(editor.handle(key(KeyCode::Left)), Edit::Unchanged);
+        editor.handle(key(KeyCode::Delete));
+        assert_eq!(editor.text(), "ello ");
+        editor.handle(key(KeyCode::End));
+        editor.handle(KeyEvent::new(KeyCode::Char('u'), KeyModifiers::CONTROL));
+        assert_eq!(editor.text(), "");
+    }
+
+    #[test]
+    fn editor_keeps_to_its_limits() {
+        let mut editor = Editor::new(4, false, |c| c.is_ascii_alphanumeric());
+        typed(&mut editor, "ab-c\u{7}你de");
+        assert_eq!(editor.text(), "abcd");
+        // Single line editors submit on Shift+Enter too
+        assert_eq!(
+            editor.handle(KeyEvent::new(KeyCode::Enter, KeyModifiers::SHIFT)),
+            Edit::Submit(String::from("abcd"))
+        );
+        assert_eq!(editor.text(), "");
+    }
+
+    #[test]
+    fn editor_lines_and_history() {
+        let mut editor = Editor::new(100, true, |_| true);
+        typed(&mut editor, "one");
+        editor.handle(KeyEvent::new(KeyCode::Enter, KeyModifiers::SHIFT));
+        typed(&mut editor, "three");
+        assert_eq!(editor.line_count(), 2);
+        editor.handle(key(KeyCode::Up));
+        assert_eq!(editor.cursor_position(), (0, 3));
+        editor.handle(key(KeyCode::Down));
+        assert_eq!(editor.cursor_position(), (1, 3));
+        assert_eq!(editor.text(), "one\nthree");
+
+        let mut editor = Editor::new(100, false, |_| true);
+        for text in ["first", "second"] {
+            typed(&mut editor, text);
+            editor.handle(key(KeyCode::Enter));
+        }
+        typed(&mut editor, "draft");
+        editor.handle(key(KeyCode::Up));
+        assert_eq!(editor.text(), "second");
+        editor.handle(key(KeyCode::Up));
+        editor.handle(key(KeyCode::Up));
+        assert_eq!(editor.text(), "first");
+        editor.handle(key(KeyCode::Down));
+        editor.handle(key(KeyCode::Down));
+        assert_eq!(editor.text(), "draft");
+    }
+}
@@ -0,0 +1,512 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+
+use crate::prelude::*;
+use config::LogRotate;
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+/// Most lines of the log `search` returns, the latest ones
+const SEARCH_MAX: usize = 50;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum EntryKind {
+    Message,
+    /// Message replayed by room owner, it was already logged by whoever was there.
+    History,
+    /// Join, leave and other notices of the room.
+    System,
+}
+
+/// One line of the conversation.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub time: DateTime<Local>,
+    pub sender: String,
+    pub text: String,
+    pub kind: EntryKind,
+}
+
+impl Entry {
+    pub fn new(sender: &str, text: &str, kind: EntryKind) -> Entry {
+        Entry {
+            time: Local::now(),
+            sender: sender.to_string(),
+            text: text.to_string(),
+            kind,
+        }
+    }
+
+    /// Entry of a message, at the time its sender wrote it.
+    pub fn of_message(message: &protocol::Message, kind: EntryKind) -> Entry {
+        Entry {
+            time: local_time(message.time),
+            ..Entry::new(&message.pro_id.id, &message.text(), kind)
+        }
+    }
+}
+
+/// Local time of milliseconds since the Unix epoch, as carried by messages.
+pub fn local_time(millis: u64) -> DateTime<Local> {
+    Local
+        .timestamp_millis_opt(millis as i64)
+        .single()
+        .unwrap_or_else(Local::now)
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ExportFormat {
+    Text,
+    JsonLines,
+    Markdown,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Text => "txt",
+            ExportFormat::JsonLines => "jsonl",
+            ExportFormat::Markdown => "md",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "txt" | "text" => Ok(ExportFormat::Text),
+            "jsonl" | "json" => Ok(ExportFormat::JsonLines),
+            "md" | "markdown" => Ok(ExportFormat::Markdown),
+            _ => Err(format!(
+                "Unknown export format `{}`, use txt, jsonl or md",
+                s
+            )),
+        }
+    }
+}
+
+/// Append-only log file of the current room.
+struct RoomLog {
+    dir: PathBuf,
+    rotate: LogRotate,
+    max_size: u64,
+    file: Option<File>,
+    /// Path of the file currently open
+    path: PathBuf,
+}
+
+lazy_static! {
+    static ref ROOM: Mutex<String> = Mutex::new(String::new());
+    /// Directory of the logs of the room, `None` when logging is off
+    static ref LOG_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+    static ref LOG: Mutex<Option<RoomLog>> = Mutex::new(None);
+    static ref TRANSCRIPT: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+}
+
+/// Start the conversation of a room, named after its owner. The log goes to its own directory when
+/// `log.enabled` of the config.
+pub fn open_room(room: &str, config: &config::Config) {
+    let room: String = room
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let dir = Some(config.log_dir.join(&room)).filter(|_| config.log_enabled);
+    *LOG.lock().unwrap() = dir.clone().map(|dir| RoomLog {
+        dir,
+        rotate: config.log_rotate,
+        max_size: config.log_max_size,
+        file: None,
+        path: PathBuf::new(),
+    });
+    *LOG_DIR.lock().unwrap() = dir;
+    *ROOM.lock().unwrap() = room;
+    TRANSCRIPT.lock().unwrap().clear();
+}
+
+/// Keep the entry for export and append it to the room log.
+///
+/// If the log can't be written, logging stops for this room and the error is returned.
+pub fn record(entry: Entry) -> io::Result<()> {
+    let result = {
+        let mut lock_log = LOG.lock().unwrap();
+        match lock_log.as_mut() {
+            Some(log) if entry.kind != EntryKind::History => {
+                let result = log.append(&entry);
+                if result.is_err() {
+                    *lock_log = None;
+                }
+                result
+            }
+            _ => Ok(()),
+        }
+    };
+    TRANSCRIPT.lock().unwrap().push(entry);
+    result
+}
+
+/// Write the conversation since joining the room. Without a path, a file named after the room
+/// and the current time is created in the working directory.
+pub fn export(format: ExportFormat, path: Option<&Path>) -> io::Result<PathBuf> {
+    let room = ROOM.lock().unwrap().clone();
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => PathBuf::from(format!(
+            "{}-{}.{}",
+            room,
+            Local::now().format("%Y%m%d-%H%M%S"),
+            format.extension()
+        )),
+    };
+
+    let mut content = String::new();
+    if format == ExportFormat::Markdown {
+        writeln!(content, "# Chat log of {}\n", room).unwrap();
+    }
+    for entry in TRANSCRIPT.lock().unwrap().iter() {
+        let line = match format {
+            ExportFormat::Text => format_text(entry),
+            ExportFormat::JsonLines => format_json(entry),
+            ExportFormat::Markdown => format_markdown(entry),
+        };
+        writeln!(content, "{}", line).unwrap();
+    }
+
+    fs::write(&path, content)?;
+    Ok(path)
+}
+
+/// Lines of the log of the current room written before `before`, in milliseconds since the Unix
+/// epoch, that have the query, case ignored. Returned with their time, oldest first.
+///
+/// Nothing is found when logging is off.
+pub fn search(query: &str, before: Option<u64>) -> io::Result<Vec<(u64, String)>> {
+    let dir = match LOG_DIR.lock().unwrap().clone() {
+        Some(dir) => dir,
+        None => return Ok(Vec::new()),
+    };
+    let mut paths = match fs::read_dir(&dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "log"))
+            .collect::<Vec<_>>(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    // Names hold the date, `chat.log` of `log.rotate = size` sorts after the rotated ones
+    paths.sort();
+
+    let query = query.to_lowercase();
+    let mut found = Vec::new();
+    for path in paths {
+        // Lines after the first of a multi-line message have no time, they take the one before
+        let mut time = None;
+        for line in fs::read_to_string(&path)?.lines() {
+            if let Some(stamp) = line.get(..19) {
+                if let Ok(stamp) = NaiveDateTime::parse_from_str(stamp, TIME_FORMAT) {
+                    time = Local
+                        .from_local_datetime(&stamp)
+                        .earliest()
+                        .map(|stamp| stamp.timestamp_millis() as u64);
+                }
+            }
+            // Times of the log are in seconds, a line of the same second as `before` may be it
+            let time = match time {
+                Some(time) if before.is_none_or(|before| time < before - before % 1000) => time,
+                _ => continue,
+            };
+            if line.to_lowercase().contains(&query) {
+                // Older logs may hold what other members sent as it came
+                found.push((time, protocol::clean_text(line)));
+            }
+        }
+    }
+    let skip = found.len().saturating_sub(SEARCH_MAX);
+    Ok(found.split_off(skip))
+}
+
+impl RoomLog {
+    fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        let path = self.current_path(entry)?;
+        if self.file.is_none() || path != self.path {
+            fs::create_dir_all(&self.dir)?;
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&path)?);
+            self.path = path;
+        }
+        let file = self.file.as_mut().unwrap();
+        writeln!(file, "{}", format_text(entry))?;
+        file.flush()
+    }
+
+    /// File to append to, rotating the full one away when `log.rotate = size`.
+    fn current_path(&mut self, entry: &Entry) -> io::Result<PathBuf> {
+        match self.rotate {
+            LogRotate::Daily => Ok(self
+                .dir
+                .join(format!("{}.log", entry.time.format("%Y-%m-%d")))),
+            LogRotate::Size => {
+                let path = self.dir.join("chat.log");
+                let size = fs::metadata(&path).map(|meta| meta.len()).unwrap_or(0);
+                if size >= self.max_size {
+                    self.file = None;
+                    let stamp = entry.time.format("%Y%m%d-%H%M%S").to_string();
+                    let mut rotated = self.dir.join(format!("chat-{}.log", stamp));
+                    let mut count = 1;
+                    while rotated.exists() {
+                        rotated = self.dir.join(format!("chat-{}-{}.log", stamp, count));
+                        count += 1;
+                    }
+                    fs::rename(&path, rotated)?;
+                }
+                Ok(path)
+            }
+        }
+    }
+}
+
+fn format_text(entry: &Entry) -> String {
+    match entry.kind {
+        EntryKind::System => format!(
+            "{} * {} {}",
+            entry.time.format(TIME_FORMAT),
+            entry.sender,
+            entry.text
+        ),
+        _ => format!(
+            "{} {}: {}",
+            entry.time.format(TIME_FORMAT),
+            entry.sender,
+            entry.text
+        ),
+    }
+}
+
+fn format_markdown(entry: &Entry) -> String {
+    match entry.kind {
+        EntryKind::System => format!(
+            "- `{}` *{} {}*",
+            entry.time.format(TIME_FORMAT),
+            entry.sender,
+            entry.text
+        ),
+        _ => format!(
+            "- `{}` **{}**: {}",
+            entry.time.format(TIME_FORMAT),
+            entry.sender,
+            entry.text
+        ),
+    }
+}
+
+fn format_json(entry: &Entry) -> String {
+    let kind = match entry.kind {
+        EntryKind::Message => "message",
+        EntryKind::History => "history",
+        EntryKind::System => "system",
+    };
+    format!(
+        "{{\"time\":\"{}\",\"sender\":\"{}\",\"kind\":\"{}\",\"text\":\"{}\"}}",
+        entry.time.to_rfc3339(),
+        json_escape(&entry.sender),
+        kind,
+        json_escape(&entry.text)
+    )
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIME: u64 = 1_700_000_000_000;
+
+    lazy_static! {
+        /// Tests share the room and its log
+        static ref SERIAL: Mutex<()> = Mutex::new(());
+    }
+
+    /// Open a room logged to a fresh directory, rotated as given or past 64 bytes.
+    fn open(room: &str, log_rotate: LogRotate) -> (std::sync::MutexGuard<'static, ()>, PathBuf) {
+        let guard = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        let dir =
+            std::env::temp_dir().join(format!("moyu-chatlog-{}-{}", std::process::id(), room));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        open_room(
+            room,
+            &config::Config {
+                log_enabled: true,
+                log_dir: dir.clone(),
+                log_rotate,
+                log_max_size: 64,
+                ..config::Config::default()
+            },
+        );
+        (guard, dir)
+    }
+
+    fn entry(seconds: u64, sender: &str, text: &str, kind: EntryKind) -> Entry {
+        Entry {
+            time: local_time(TIME + seconds * 1000),
+            ..Entry::new(sender, text, kind)
+        }
+    }
+
+    fn logs(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn size_rotation_keeps_every_line() {
+        let (_guard, dir) = open("size", LogRotate::Size);
+        for second in 0..5 {
+            record(entry(
+                second,
+                "bob",
+                &format!("hello {}", second),
+                EntryKind::Message,
+            ))
+            .unwrap();
+        }
+        // Replayed messages were logged by whoever was there
+        record(entry(5, "bob", "hello again", EntryKind::History)).unwrap();
+
+        let names = logs(&dir.join("size"));
+        assert_eq!(names.len(), 3, "{:?}", names);
+        assert_eq!(names.last().unwrap(), "chat.log");
+        let lines: Vec<_> = names
+            .iter()
+            .flat_map(|name| {
+                let content = fs::read_to_string(dir.join("size").join(name)).unwrap();
+                content.lines().map(String::from).collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(lines.len(), 5);
+        for (second, line) in lines.iter().enumerate() {
+            assert!(
+                line.ends_with(&format!("bob: hello {}", second)),
+                "{}",
+                line
+            );
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn daily_rotation_names_files_by_date() {
+        let (_guard, dir) = open("daily", LogRotate::Daily);
+        record(entry(0, "bob", "today", EntryKind::Message)).unwrap();
+        record(entry(24 * 60 * 60, "bob", "tomorrow", EntryKind::Message)).unwrap();
+
+        let day = |seconds: u64| {
+            format!(
+                "{}.log",
+                local_time(TIME + seconds * 1000).format("%Y-%m-%d")
+            )
+        };
+        assert_eq!(logs(&dir.join("daily")), vec![day(0), day(24 * 60 * 60)]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn search_finds_older_lines_of_every_file() {
+        let (_guard, dir) = open("search", LogRotate::Size);
+        record(entry(0, "bob", "Hello there", EntryKind::Message)).unwrap();
+        record(entry(1, "carol", "nothing", EntryKind::Message)).unwrap();
+        record(entry(
+            2,
+            "bob",
+            "hello\nsecond line hello",
+            EntryKind::Message,
+        ))
+        .unwrap();
+        record(entry(3, "bob", "HELLO late", EntryKind::Message)).unwrap();
+
+        let found = search("hello", None).unwrap();
+        let times: Vec<_> = found.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, vec![TIME, TIME + 2000, TIME + 2000, TIME + 3000]);
+        assert!(found[2].1.starts_with("second line"));
+
+        // A line of the same second as `before` may be the one searched from
+        let found = search("hello", Some(TIME + 3500)).unwrap();
+        assert_eq!(found.len(), 3);
+        assert!(search("nobody", None).unwrap().is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn export_writes_every_format() {
+        let (_guard, dir) = open("export", LogRotate::Daily);
+        record(entry(0, "bob", "say \"hi\"\tnow", EntryKind::Message)).unwrap();
+        record(entry(1, "carol", "joined", EntryKind::System)).unwrap();
+        let stamp = |seconds: u64| {
+            local_time(TIME + seconds * 1000)
+                .format(TIME_FORMAT)
+                .to_string()
+        };
+
+        let path = export(ExportFormat::Text, Some(&dir.join("chat.txt"))).unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            format!(
+                "{} bob: say \"hi\"\tnow\n{} * carol joined\n",
+                stamp(0),
+                stamp(1)
+            )
+        );
+
+        let path = export(ExportFormat::Markdown, Some(&dir.join("chat.md"))).unwrap();
+        assert_eq!(
+            fs::read_to_string(path).unwrap(),
+            format!(
+                "# Chat log of export\n\n- `{}` **bob**: say \"hi\"\tnow\n- `{}` *carol joined*\n",
+                stamp(0),
+                stamp(1)
+            )
+        );
+
+        let path = export(ExportFormat::JsonLines, Some(&dir.join("chat.jsonl"))).unwrap();
+        let content = fs::read_to_string(path).unwrap();
+        let lines: Vec<_> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(
+            "\"sender\":\"bob\",\"kind\":\"message\",\"text\":\"say \\\"hi\\\"\\tnow\"}"
+        ));
+        assert!(lines[1].contains("\"kind\":\"system\""));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
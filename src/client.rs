@@ -1,55 +1,356 @@
 use super::prelude::*;
+use crate::chatlog::{Entry, EntryKind, ExportFormat};
+use crate::command::CommandKind;
+use crate::event::{Event as RoomEvent, EventBus, EventKind, JoinResult};
+use crate::memo::MemoStatus;
+use crate::transfer::TransferState;
 use crossterm::{
     cursor,
-    event::{self, Event, KeyCode, KeyEvent},
+    event::{self, Event, KeyCode, KeyEvent, KeyModifiers, MouseEvent, MouseEventKind},
     queue,
     terminal::{self, ClearType},
 };
 use std::{
     collections::VecDeque,
+    convert::TryFrom,
     io::Write,
     net::{SocketAddr, UdpSocket},
     process::exit,
     str::FromStr,
-    sync::{Arc, Mutex},
-    thread,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
-const DEFAULT_PROTOCOL: &'static str = "MOYU";
-const EXIT_COMMAND: &'static str = "exit";
+const DEFAULT_PROTOCOL: &str = "MOYU";
+/// Typed instead of an id or an address to quit, in a room it is `/quit`
+const EXIT_COMMAND: &str = "exit";
 const KEY_MAX_LEN: usize = 16;
+/// Bytes of an address typed to join a room, enough for IPv6 with a port
+const ADDR_MAX_LEN: usize = 64;
+/// Bytes of a typed message, so its frame fits the receive buffer of room owner
+const INPUT_MAX_LEN: usize = 360;
 const TIMEOUT_COUNT: usize = 30;
+/// How long to wait for room owner to acknowledge our leave.
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Exit code used when shutting down on Ctrl+C, 128 + SIGINT. The handler of `ctrlc` doesn't tell
+/// which signal came, so SIGTERM and SIGHUP exit with it too, not with 143 and 129.
+const INTERRUPTED_CODE: i32 = 130;
+/// First delay between join retries after losing room owner, doubled after every try.
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(30);
+/// How long a lost room owner is waited for before the room is given up.
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Shown when room owner didn't come back in `RECONNECT_TIMEOUT`.
+const ROOM_LOST: &str = "Room owner didn't come back, messages not sent are dropped";
+/// Messages typed while disconnected that are kept to be sent after reconnecting.
+const PENDING_MAX: usize = 50;
+/// Least time between two typing frames, less than `roster::TYPING_TIMEOUT` so it doesn't fade.
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Why `communication` returned.
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum ChatEnd {
+    Exit,
+    Rejoin,
+}
+
+/// What `shutdown` needs to leave the room and stop the receive thread.
+struct Session {
+    socket: Arc<UdpSocket>,
+    bus: Arc<EventBus>,
+    send_addr: Option<SocketAddr>,
+    receive_thread: Option<JoinHandle<()>>,
+    transfer_thread: Option<JoinHandle<()>>,
+}
+
+lazy_static! {
+    static ref SESSION: Mutex<Option<Session>> = Mutex::new(None);
+}
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
 
 pub fn run() {
+    if let Err(e) = config::load() {
+        println!("{}", e);
+        exit(1);
+    }
     buf::initialize();
+    ctrlc::set_handler(|| shutdown(INTERRUPTED_CODE)).unwrap();
     protocol::set_protocol(DEFAULT_PROTOCOL.to_string());
     input_identity();
 
     let identity = choose_owner();
-    let mess_que = Arc::new(Mutex::new(VecDeque::<protocol::Message>::new()));
-    let socket = Arc::new(UdpSocket::bind(server::get_local_addr().unwrap()).unwrap());
+    let socket = match bind(&identity) {
+        Ok(socket) => Arc::new(socket),
+        Err(e) => {
+            println!("Can't open the room: {}", e);
+            exit(1);
+        }
+    };
+    // Keys come one by one from here on, Ctrl+C too, it no longer raises SIGINT
+    terminal::enable_raw_mode().unwrap();
+    let bus = Arc::new(EventBus::new());
+    socket
+        .set_read_timeout(Some(server::RECEIVE_TIMEOUT))
+        .unwrap();
+    // Subscribe before joining, so the banner of our own join is not missed
+    let events = bus.subscribe(&[
+        EventKind::ChatMessage,
+        EventKind::History,
+        EventKind::MemberJoined,
+        EventKind::MemberLeft,
+        EventKind::MemberPresent,
+        EventKind::MemberRenamed,
+        EventKind::Presence,
+        EventKind::RoomClosed,
+        EventKind::JoinResult,
+        EventKind::Heartbeat,
+        EventKind::Memo,
+        EventKind::MemoReceipt,
+        EventKind::Private,
+        EventKind::TransferStatus,
+        EventKind::Error,
+    ]);
 
-    {
-        let copy_mess_que = mess_que.clone();
+    let receive_thread = {
+        let copy_bus = bus.clone();
         let copy_socket = socket.clone();
         thread::spawn(move || {
-            server::receive(copy_socket, copy_mess_que);
+            server::receive(copy_socket, copy_bus);
         })
     };
+    let transfer_thread = transfer::start(bus.clone(), socket.clone());
+    *SESSION.lock().unwrap() = Some(Session {
+        socket: socket.clone(),
+        bus: bus.clone(),
+        send_addr: None,
+        receive_thread: Some(receive_thread),
+        transfer_thread: Some(transfer_thread),
+    });
 
-    let send_addr = if identity.is_room_joiner() {
-        join_room(mess_que.clone(), socket.clone())
+    let mut send_addr = if identity.is_room_joiner() {
+        join_room(&bus, socket.clone())
     } else {
         server::set_key(input_key());
-        server::send_message_to(
-            &protocol::Message::new(server::Code::Request as u8, &server::get_key()),
+        if let Err(e) = server::send_message_to(
+            &server::join_request(&server::get_key()),
             &socket.local_addr().unwrap(),
             socket.clone(),
-        );
+        ) {
+            buf::print_error(&e);
+            shutdown(1);
+        }
         socket.local_addr().unwrap()
     };
-    communication(socket, send_addr, mess_que);
+    loop {
+        set_send_addr(Some(send_addr));
+        match communication(socket.clone(), send_addr, &events) {
+            ChatEnd::Exit => break,
+            ChatEnd::Rejoin => {
+                set_send_addr(None);
+                buf::clear_buf();
+                posts::clear();
+                send_addr = join_room(&bus, socket.clone());
+            }
+        }
+    }
+    shutdown(0);
+}
+
+/// Joiner side state of the link to room owner.
+///
+/// Room owner is lost when it stops answering heartbeats. The join request is then retried with
+/// the saved key, waiting longer after each try, and messages are held until it answers again.
+/// After `RECONNECT_TIMEOUT` without an answer the room is given up.
+struct Connection {
+    connected: bool,
+    last_alive: Instant,
+    /// When the last heartbeat was sent, to time the answer
+    heartbeat_sent: Option<Instant>,
+    /// Round trip of the last heartbeat answered
+    latency: Option<Duration>,
+    next_heartbeat: Instant,
+    next_retry: Instant,
+    retry_delay: Duration,
+    pending: VecDeque<(String, server::Style)>,
+}
+
+impl Connection {
+    fn new() -> Connection {
+        let now = Instant::now();
+        Connection {
+            connected: true,
+            last_alive: now,
+            heartbeat_sent: None,
+            latency: None,
+            next_heartbeat: now,
+            next_retry: now,
+            retry_delay: RETRY_MIN,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Send heartbeat or join retry when it is time to. Returns the line to show if the link was just lost.
+    ///
+    /// A send that fails, while the network is down, is left to the missing answer to tell.
+    fn tick(&mut self, send_addr: &SocketAddr, socket: Arc<UdpSocket>) -> Option<String> {
+        let now = Instant::now();
+        if self.connected {
+            if now.duration_since(self.last_alive) > server::ALIVE_TIMEOUT {
+                self.connected = false;
+                self.latency = None;
+                self.next_retry = now;
+                self.retry_delay = RETRY_MIN;
+                return Some(buf::paint(
+                    "Lost connection to room owner, reconnecting...",
+                    buf::Tone::Error,
+                ));
+            }
+            if now >= self.next_heartbeat {
+                let _ = server::send_message_to(
+                    &protocol::Message::new(server::Code::Heartbeat as u8, ""),
+                    send_addr,
+                    socket,
+                );
+                self.heartbeat_sent = Some(now);
+                self.next_heartbeat = now + server::HEARTBEAT_INTERVAL;
+            }
+        } else if now >= self.next_retry {
+            let _ = server::send_message_to(
+                &server::join_request(&server::get_key()),
+                send_addr,
+                socket,
+            );
+            self.next_retry = now + self.retry_delay;
+            self.retry_delay = (self.retry_delay * 2).min(RETRY_MAX);
+        }
+        None
+    }
+
+    /// Room owner has been lost for longer than `RECONNECT_TIMEOUT`.
+    fn given_up(&self) -> bool {
+        !self.connected && self.last_alive.elapsed() > RECONNECT_TIMEOUT
+    }
+
+    fn alive(&mut self) {
+        self.last_alive = Instant::now();
+    }
+
+    /// Status bar of the room, `info` is shown at its end.
+    fn status(&self, info: &str) -> buf::Status {
+        buf::Status {
+            room: format!("Room of {}", roster::owner().unwrap_or_default()),
+            connected: self.connected,
+            latency: self.latency,
+            info: info.to_string(),
+        }
+    }
+
+    fn heartbeat_answered(&mut self) {
+        if let Some(sent) = self.heartbeat_sent.take() {
+            self.latency = Some(sent.elapsed());
+        }
+        self.alive();
+    }
+
+    /// Room owner accepted us again, returns the messages to send now.
+    fn reconnected(&mut self) -> Vec<(String, server::Style)> {
+        self.connected = true;
+        self.alive();
+        self.pending.drain(..).collect()
+    }
+
+    fn hold(&mut self, message: (String, server::Style)) {
+        if self.pending.len() >= PENDING_MAX {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(message);
+    }
+}
+
+/// What we told the room we are doing.
+struct Activity {
+    last_key: Instant,
+    typing_sent: Option<Instant>,
+    idle: bool,
+    away: bool,
+}
+
+impl Activity {
+    fn new() -> Activity {
+        Activity {
+            last_key: Instant::now(),
+            typing_sent: None,
+            idle: false,
+            away: false,
+        }
+    }
+
+    /// A key was pressed. Returns the presence to send if we were idle.
+    fn key_pressed(&mut self) -> Option<server::Presence> {
+        self.last_key = Instant::now();
+        if self.idle {
+            self.idle = false;
+            if !self.away {
+                return Some(server::Presence::Active);
+            }
+        }
+        None
+    }
+
+    /// Text is being typed. Returns true if it is time to tell it again.
+    fn typing(&mut self) -> bool {
+        match self.typing_sent {
+            Some(sent) if sent.elapsed() < TYPING_INTERVAL => false,
+            _ => {
+                self.typing_sent = Some(Instant::now());
+                true
+            }
+        }
+    }
+
+    /// The text was sent, others stop showing us typing when they get it.
+    fn sent(&mut self) {
+        self.typing_sent = None;
+    }
+
+    /// Returns true when we just became idle.
+    fn tick(&mut self) -> bool {
+        if !self.idle && !self.away && self.last_key.elapsed() > roster::IDLE_AFTER {
+            self.idle = true;
+            return true;
+        }
+        false
+    }
+}
+
+/// Tell room owner what we are doing, nothing is kept while disconnected.
+fn send_presence(
+    presence: server::Presence,
+    status: &str,
+    connection: &Connection,
+    send_addr: &SocketAddr,
+    socket: Arc<UdpSocket>,
+) {
+    if connection.connected {
+        let _ = server::send_message_to(
+            &server::presence_update(presence, status),
+            send_addr,
+            socket,
+        );
+    }
+}
+
+fn set_send_addr(send_addr: Option<SocketAddr>) {
+    transfer::set_send_addr(send_addr);
+    if let Some(session) = SESSION.lock().unwrap().as_mut() {
+        session.send_addr = send_addr;
+    }
 }
 
 fn input_identity() -> String {
@@ -61,36 +362,28 @@ fn input_identity() -> String {
         stdout.flush().unwrap();
         loop {
             match io::stdin().read_line(&mut id) {
-                Ok(_) => {
-                    let len = if protocol::ID_LEN > id.len() {
-                        id.len()
-                    } else {
-                        protocol::ID_LEN
-                    };
-                    id = id[..len].trim_end().trim_start().to_string();
-                }
+                Ok(_) => id = protocol::clean_id(&id),
                 Err(e) => {
                     buf::print_error(&e);
                     exit(e.kind() as i32);
                 }
             }
             if id == EXIT_COMMAND {
-                exit_client(0);
-            } else if id.len() <= 0 {
+                shutdown(0);
+            } else if id.is_empty() {
                 id = "None".to_string();
             }
-            protocol::set_id(&id);
-
-            if protocol::Message::parse_id(&protocol::get_id().unwrap()) == "" {
-                println!("Please input valid id(not all space and zero): ");
-                stdout.flush().unwrap();
-            } else {
+            if protocol::is_valid_id(&id) {
+                protocol::set_id(&id);
                 break;
             }
+            println!("Please input valid id(no space): ");
+            stdout.flush().unwrap();
+            id.clear();
         }
         println!(
             "Enter to ensure your id(ESC to cancel): [{}]",
-            protocol::Message::parse_id(&protocol::get_id().unwrap())
+            protocol::get_id().unwrap()
         );
         stdout.flush().unwrap();
         let is_ensure;
@@ -122,6 +415,15 @@ fn input_identity() -> String {
     }
 }
 
+/// Socket of the room, on `room.port` for room owner so members find it again after a restart.
+fn bind(identity: &server::Identity) -> io::Result<UdpSocket> {
+    let mut addr = server::get_local_addr()?;
+    if !identity.is_room_joiner() {
+        addr.set_port(config::get().room_port);
+    }
+    UdpSocket::bind(addr)
+}
+
 fn choose_owner() -> server::Identity {
     let mut stdout = io::stdout();
 
@@ -157,12 +459,8 @@ fn choose_owner() -> server::Identity {
     server::get_identity()
 }
 
-fn join_room(
-    mess_que: Arc<Mutex<VecDeque<protocol::Message>>>,
-    socket: Arc<UdpSocket>,
-) -> SocketAddr {
-    let mut request_message =
-        protocol::Message::new(server::Code::Request as u8, &protocol::get_id().unwrap());
+fn join_room(bus: &EventBus, socket: Arc<UdpSocket>) -> SocketAddr {
+    let join_results = bus.subscribe(&[EventKind::JoinResult]);
     let mut stdout = io::stdout();
     queue!(stdout, terminal::Clear(ClearType::All),).unwrap();
 
@@ -170,38 +468,36 @@ fn join_room(
         let room_addr = input_ip();
 
         let key = input_key();
-        request_message.message = key;
+        // Saved to join again when the connection is lost
+        server::set_key(key.clone());
+        let request_message = server::join_request(&key);
         print!("Join");
 
         let mut loading_count = 0;
-        let join_flag = loop {
-            server::send_message_to(&request_message, &room_addr, socket.clone());
+        let join_result = loop {
+            let _ = server::send_message_to(&request_message, &room_addr, socket.clone());
             print!(".");
             stdout.flush().unwrap();
 
-            let mut lock_mess_que = mess_que.lock().unwrap();
-
-            match lock_mess_que.pop_front() {
-                Some(message) => {
-                    if message.message == server::JOIN_SUCCESS.to_string() {
-                        break true;
-                    } else if message.message == server::JOIN_FAILED.to_string() {
-                        break false;
-                    }
-                }
-                None => {}
+            match join_results.recv_timeout(Duration::from_secs_f32(1.5)) {
+                Ok(RoomEvent::JoinResult(result)) => break Some(result),
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break None,
             };
             loading_count += 1;
             if loading_count >= TIMEOUT_COUNT {
-                break false;
+                break None;
             }
-            thread::sleep(Duration::from_secs_f32(1.5));
         };
 
-        if !join_flag {
-            print!("\nTime out or False key!\nJoin faild!");
-        } else {
-            return room_addr;
+        match join_result {
+            Some(JoinResult::Success { owner, .. }) => {
+                roster::reset(&owner);
+                roster::join(&protocol::get_id().unwrap());
+                return room_addr;
+            }
+            Some(JoinResult::Failed { reason }) => print!("\n{}!\nJoin faild!", reason),
+            None => print!("\nTime out!\nJoin faild!"),
         }
         stdout.flush().unwrap();
         thread::sleep(Duration::from_secs_f32(2.0));
@@ -210,178 +506,984 @@ fn join_room(
 
 fn input_ip() -> SocketAddr {
     let out_head = String::from("Enter ip to join room > ");
-    let mut input = String::new();
-    let mut room_addr;
+    let mut editor = buf::Editor::new(ADDR_MAX_LEN, false, |c| !c.is_whitespace());
     let mut stdout = io::stdout();
     buf::clear_all();
-    buf::print_input(&out_head, &input, 0);
+    buf::print_editor(&out_head, &editor, 0, 1);
     loop {
-        if let Ok(Event::Key(KeyEvent { code, .. })) = event::read() {
-            match code {
-                KeyCode::Backspace => {
-                    input.pop();
-                }
-                KeyCode::Enter => {
-                    if input.len() <= 0 {
-                        continue;
-                    }
+        if let Ok(Event::Key(key)) = event::read() {
+            if is_interrupt(&key) {
+                shutdown(INTERRUPTED_CODE);
+            }
+            match editor.handle(key) {
+                buf::Edit::Submit(input) if input.is_empty() => {}
+                buf::Edit::Submit(input) => {
                     if input == EXIT_COMMAND {
-                        exit_client(0);
+                        shutdown(0);
                     }
-                    room_addr = SocketAddr::from_str(&input);
-                    input.clear();
-
-                    if let Err(_) = room_addr {
-                        println!("Please input valid ip!");
-                        stdout.flush().unwrap();
-                        thread::sleep(Duration::from_secs_f32(2.5));
-                        queue!(stdout, terminal::Clear(ClearType::All)).unwrap();
-                        stdout.flush().unwrap();
-                        buf::print_input(&out_head, &input, 0);
-                        continue;
-                    }
-                    break;
-                }
-                KeyCode::Char(c) => {
-                    if c.to_string().trim() != "" {
-                        input.push(c);
+                    match SocketAddr::from_str(&input) {
+                        Ok(room_addr) => return room_addr,
+                        Err(_) => {
+                            buf::println("Please input valid ip!", 1);
+                            thread::sleep(Duration::from_secs_f32(2.5));
+                            queue!(stdout, terminal::Clear(ClearType::All)).unwrap();
+                            stdout.flush().unwrap();
+                        }
                     }
                 }
-                _ => {}
-            };
-            match code {
-                KeyCode::Backspace | KeyCode::Char(_) | KeyCode::Enter => {
-                    buf::print_input(&out_head, &input, 0);
-                }
-                _ => {}
-            };
+                buf::Edit::Changed => {}
+                buf::Edit::Unchanged => continue,
+            }
+            buf::print_editor(&out_head, &editor, 0, 1);
         }
     }
-    room_addr.unwrap()
 }
 
 fn input_key() -> String {
     let head = String::from("Enter key > ");
-    let mut input = String::new();
+    let mut editor = buf::Editor::new(KEY_MAX_LEN, false, |c| !c.is_whitespace());
     buf::clear_all();
-    buf::print_input(&head, &input, 0);
+    buf::print_editor(&head, &editor, 0, 1);
     loop {
-        if let Ok(Event::Key(KeyEvent { code, .. })) = event::read() {
-            match code {
-                KeyCode::Backspace => {
-                    input.pop();
-                }
-                KeyCode::Enter => {
-                    if input.len() <= 0 {
-                        continue;
-                    }
-                    break;
-                }
-                KeyCode::Char(c) => {
-                    if c.to_string().trim() != "" {
-                        if input.len() < KEY_MAX_LEN {
-                            input.push(c);
-                        }
-                    }
-                }
-                _ => {}
-            };
-
-            match code {
-                KeyCode::Backspace | KeyCode::Enter | KeyCode::Char(_) => {
-                    buf::print_input(&head, &input, 0);
-                }
-                _ => {}
-            };
+        if let Ok(Event::Key(key)) = event::read() {
+            if is_interrupt(&key) {
+                shutdown(INTERRUPTED_CODE);
+            }
+            match editor.handle(key) {
+                buf::Edit::Submit(input) if input.is_empty() => {}
+                buf::Edit::Submit(input) => return input.trim().to_string(),
+                buf::Edit::Changed => {}
+                buf::Edit::Unchanged => continue,
+            }
+            buf::print_editor(&head, &editor, 0, 1);
         }
     }
-    input.trim().to_string()
 }
 
+/// Chat until the user exits or room owner closes the room.
+///
+/// On `ChatEnd::Exit` the caller is responsible to leave the room.
 fn communication(
     socket: Arc<UdpSocket>,
     send_addr: SocketAddr,
-    mess_que: Arc<Mutex<VecDeque<protocol::Message>>>,
-) {
+    events: &Receiver<RoomEvent>,
+) -> ChatEnd {
     buf::clear_all();
 
     let input_head = String::from("Input message > ");
-    let ip_head = if server::is_room_owner() {
+    let info = if server::is_room_owner() {
         format!(
-            "(Your ip: {}, Key: {})",
-            socket.local_addr().unwrap().to_string(),
+            "Your ip: {}, Key: {}",
+            socket.local_addr().unwrap(),
             server::get_key()
         )
     } else {
-        format!("(Your ip: {})", socket.local_addr().unwrap().to_string())
-    };
-    let mut input = String::new();
-    let input_line = 22;
-    let ter_size = terminal::size().unwrap().0 as usize;
-    let input_size = if input_head.len() > ter_size {
-        0
-    }else{
-        ter_size - input_head.len()
+        format!("Your ip: {}", socket.local_addr().unwrap())
     };
-    buf::print_input(&input_head, &input, input_line);
-    buf::println(&ip_head, 24);
+    let mut editor = buf::Editor::new(INPUT_MAX_LEN, true, |_| true);
+    let mut connection = Connection::new();
+    let mut activity = Activity::new();
+    // Text typed before Ctrl+F, given back once the search is sent
+    let mut search_draft: Option<String> = None;
+    notify::open_room(&send_addr.to_string());
+    if server::is_room_owner() {
+        roster::reset(&protocol::get_id().unwrap());
+    }
+    // Port of room owner changes with `room.port = 0`, its id is what stays the same
+    chatlog::open_room(&roster::owner().unwrap_or_default(), &config::get());
+    buf::set_input_rows(1);
+    buf::set_status(connection.status(&info));
+    draw_chat(&input_head, &editor);
     loop {
-        if let Some(message) = get_new_message(mess_que.clone()) {
-            if let server::Code::Message = server::Code::from(message.code) {
-                buf::push_message(&message.to_string());
+        while let Ok(event) = events.try_recv() {
+            match &event {
+                RoomEvent::MemberJoined { id } | RoomEvent::MemberPresent { id } => {
+                    roster::join(id)
+                }
+                RoomEvent::MemberLeft { id } => roster::leave(id),
+                RoomEvent::MemberRenamed { old, new } => {
+                    roster::rename(old, new);
+                    posts::rename(old, new);
+                    if protocol::get_id().as_ref() == Some(old) {
+                        protocol::set_id(new);
+                    }
+                }
+                RoomEvent::ChatMessage(message) => {
+                    roster::active(&message.pro_id.id);
+                    notify_post(message);
+                }
+                RoomEvent::Memo { from, text } | RoomEvent::Private { from, text } => {
+                    notify(from, text, true)
+                }
+                RoomEvent::Presence {
+                    id,
+                    presence,
+                    status,
+                } if protocol::get_id().as_ref() != Some(id) => {
+                    let was = roster::set_presence(id, *presence, status);
+                    if let Some(line) = presence_line(id, was, *presence, status) {
+                        buf::push_message(&line);
+                    }
+                }
+                _ => {}
+            }
+            let entry = match &event {
+                RoomEvent::ChatMessage(message) => show_post(message, false),
+                RoomEvent::History(message) => show_post(message, true),
+                RoomEvent::TransferStatus { id, name, state } => {
+                    show_transfer(*id, name, state);
+                    event_entry(&event)
+                }
+                _ => {
+                    if let Some(line) = format_event(&event) {
+                        buf::push_message(&line);
+                    }
+                    event_entry(&event)
+                }
+            };
+            buf::print_message();
+            if let Some(entry) = entry {
+                record(entry);
+            }
+            match event {
+                RoomEvent::RoomClosed { .. } => {
+                    set_send_addr(None);
+                    return room_closed(server::ROOM_CLOSED);
+                }
+                // Room owner came back with another key, retrying would not help
+                RoomEvent::JoinResult(JoinResult::Failed { reason }) if !connection.connected => {
+                    set_send_addr(None);
+                    return room_closed(&reason);
+                }
+                RoomEvent::Heartbeat => connection.heartbeat_answered(),
+                RoomEvent::JoinResult(JoinResult::Success { owner, .. })
+                    if !connection.connected =>
+                {
+                    roster::reset(&owner);
+                    roster::join(&protocol::get_id().unwrap());
+                    buf::push_message(&buf::paint("Reconnected", buf::Tone::Success));
+                    buf::print_message();
+                    for (text, style) in connection.reconnected() {
+                        send_text(&text, style, &mut connection, &send_addr, socket.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+        if server::get_identity().is_room_joiner() {
+            if let Some(line) = connection.tick(&send_addr, socket.clone()) {
+                buf::push_message(&line);
                 buf::print_message();
             }
+            if connection.given_up() {
+                set_send_addr(None);
+                return room_closed(ROOM_LOST);
+            }
+        }
+        if activity.tick() {
+            send_presence(
+                server::Presence::Idle,
+                "",
+                &connection,
+                &send_addr,
+                socket.clone(),
+            );
         }
+        buf::set_status(connection.status(&info));
+        buf::print_status();
+        buf::print_sidebar();
         if let Ok(true) = event::poll(Duration::from_millis(100)) {
-            if let Ok(Event::Key(KeyEvent { code, .. })) = event::read() {
-                match code {
-                    KeyCode::Enter => {
-                        input = input.trim_end().trim_start().to_string();
-                        if input.len() > 0 {
-                            if input == EXIT_COMMAND {
-                                server::send_message_to(
-                                    &protocol::Message::new(
-                                        server::Code::Request as u8,
-                                        &server::EXIT_ROOM.to_string(),
-                                    ),
+            let key = match event::read() {
+                Ok(Event::Key(key)) => key,
+                Ok(Event::Mouse(MouseEvent { kind, .. })) => {
+                    match kind {
+                        MouseEventKind::ScrollUp => buf::wheel_up(),
+                        MouseEventKind::ScrollDown => buf::wheel_down(),
+                        _ => continue,
+                    }
+                    buf::print_message();
+                    continue;
+                }
+                Ok(Event::Resize(width, height)) => {
+                    buf::resize(width, height);
+                    buf::set_input_rows(buf::editor_height(&input_head, &editor));
+                    draw_chat(&input_head, &editor);
+                    continue;
+                }
+                Err(_) => continue,
+            };
+            if let Some(presence) = activity.key_pressed() {
+                send_presence(presence, "", &connection, &send_addr, socket.clone());
+            }
+            if buf::searching() {
+                match key.code {
+                    KeyCode::Char('n') if key.modifiers.is_empty() => {
+                        buf::search_next(true);
+                        buf::print_message();
+                        continue;
+                    }
+                    KeyCode::Char('N') => {
+                        buf::search_next(false);
+                        buf::print_message();
+                        continue;
+                    }
+                    KeyCode::Esc => {
+                        buf::end_search();
+                        buf::print_message();
+                        continue;
+                    }
+                    KeyCode::PageUp | KeyCode::PageDown => {}
+                    _ => {
+                        buf::end_search();
+                        buf::print_message();
+                    }
+                }
+            }
+            if is_interrupt(&key) {
+                return ChatEnd::Exit;
+            }
+            match key.code {
+                KeyCode::PageUp => {
+                    buf::page_up();
+                    buf::print_message();
+                    continue;
+                }
+                KeyCode::PageDown => {
+                    buf::page_down();
+                    buf::print_message();
+                    continue;
+                }
+                _ => {}
+            }
+            let edit = match key.code {
+                KeyCode::Tab => complete(&mut editor),
+                KeyCode::Char('f') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    search_draft = Some(editor.text());
+                    editor.set_text(&format!("{} ", command::get(CommandKind::Search).name));
+                    buf::Edit::Changed
+                }
+                _ => editor.handle(key),
+            };
+            match edit {
+                buf::Edit::Submit(input) => {
+                    let input = input.trim();
+                    if !input.is_empty() {
+                        let end = match command::parse(input) {
+                            Ok(command::Input::Text(text)) => {
+                                send_text(
+                                    text,
+                                    server::Style::Plain,
+                                    &mut connection,
                                     &send_addr,
                                     socket.clone(),
                                 );
-                                exit_client(0);
+                                activity.sent();
+                                None
                             }
-                            server::send_message_to(
-                                &protocol::Message::new(server::Code::Message as u8, &input),
+                            Ok(command::Input::Command(kind, args)) => run_command(
+                                kind,
+                                args,
+                                &mut connection,
+                                &mut activity,
                                 &send_addr,
                                 socket.clone(),
-                            );
-                            input.clear();
+                            ),
+                            Err(err) => {
+                                buf::push_message(&buf::paint(&err, buf::Tone::Error));
+                                buf::print_message();
+                                None
+                            }
+                        };
+                        if let Some(end) = end {
+                            return end;
                         }
                     }
-                    KeyCode::Backspace | KeyCode::Delete => {
-                        input.pop();
+                    if let Some(draft) = search_draft.take() {
+                        editor.set_text(&draft);
                     }
-                    KeyCode::Char(c) => {
-                        if input.len() < input_size {
-                            input.push(c);
-                        }
+                }
+                buf::Edit::Changed => {
+                    let text = editor.text();
+                    if !text.trim().is_empty() && !text.starts_with('/') && activity.typing() {
+                        send_presence(
+                            server::Presence::Typing,
+                            "",
+                            &connection,
+                            &send_addr,
+                            socket.clone(),
+                        );
                     }
-                    _ => {}
-                };
+                }
+                buf::Edit::Unchanged => continue,
+            }
+
+            if buf::set_input_rows(buf::editor_height(&input_head, &editor)) {
+                draw_chat(&input_head, &editor);
+            } else {
+                let layout = buf::layout();
+                buf::print_editor(&input_head, &editor, layout.input_line(), layout.input_rows);
+            }
+        }
+    }
+}
+
+/// Draw the whole chat screen again, after it was cleared or the terminal resized.
+fn draw_chat(input_head: &str, editor: &buf::Editor) {
+    let layout = buf::layout();
+    buf::clear_all();
+    buf::print_message();
+    buf::print_editor(input_head, editor, layout.input_line(), layout.input_rows);
+}
+
+/// Let the user choose between joining another room and quitting, once the room is closed.
+fn room_closed(reason: &str) -> ChatEnd {
+    buf::print_input(
+        &buf::paint(reason, buf::Tone::Error),
+        " Enter to join another room, ESC to quit",
+        buf::input_line(),
+    );
+    loop {
+        if let Ok(Event::Key(key)) = event::read() {
+            match key.code {
+                KeyCode::Enter => return ChatEnd::Rejoin,
+                KeyCode::Esc => return ChatEnd::Exit,
+                _ if is_interrupt(&key) => return ChatEnd::Exit,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Ctrl+C, read as a key in raw mode.
+fn is_interrupt(key: &KeyEvent) -> bool {
+    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL)
+}
+
+/// Complete the word before the cursor with Tab: a command name, or the id of a member. When
+/// several fit, it goes as far as they agree and lists them.
+fn complete(editor: &mut buf::Editor) -> buf::Edit {
+    let (word, first) = editor.word_before_cursor();
+    let ids: Vec<String> = roster::members()
+        .into_iter()
+        .map(|member| member.id)
+        .collect();
+    let candidates = command::completions(&word, first, &ids);
+    match candidates.len() {
+        0 => return buf::Edit::Unchanged,
+        1 => editor.replace_word_before_cursor(&format!("{} ", candidates[0])),
+        _ => {
+            let prefix = command::common_prefix(&candidates);
+            if prefix.len() > word.len() {
+                editor.replace_word_before_cursor(&prefix);
+            } else {
+                buf::push_message(&buf::paint(&candidates.join("  "), buf::Tone::Muted));
+                buf::print_message();
+                return buf::Edit::Unchanged;
+            }
+        }
+    }
+    buf::Edit::Changed
+}
+
+/// Send a message to the room, or keep it to send after reconnecting.
+fn send_text(
+    text: &str,
+    style: server::Style,
+    connection: &mut Connection,
+    send_addr: &SocketAddr,
+    socket: Arc<UdpSocket>,
+) {
+    if connection.connected {
+        if let Err(e) =
+            server::send_message_to(&server::chat_message(text, style), send_addr, socket)
+        {
+            buf::push_message(&buf::paint(
+                &format!("Message not sent: {}", e),
+                buf::Tone::Error,
+            ));
+        }
+    } else {
+        let author = buf::paint_id(&protocol::get_id().unwrap());
+        buf::push_message(&format!(
+            "{}{} {}",
+            match style {
+                server::Style::Action => format!("* {} ", author),
+                server::Style::Plain => format!("{}:\t", author),
+            },
+            text,
+            buf::paint("(pending)", buf::Tone::Muted)
+        ));
+        buf::print_message();
+        connection.hold((text.to_string(), style));
+    }
+}
+
+/// Run a command typed in the chat input, its arguments are already checked by `command::parse`.
+/// Returns how the chat ends if the command ends it.
+fn run_command(
+    kind: CommandKind,
+    args: &str,
+    connection: &mut Connection,
+    activity: &mut Activity,
+    send_addr: &SocketAddr,
+    socket: Arc<UdpSocket>,
+) -> Option<ChatEnd> {
+    let line = match kind {
+        CommandKind::Quit => return Some(ChatEnd::Exit),
+        CommandKind::Help => {
+            for line in command::help(args) {
+                buf::push_message(&line);
+            }
+            None
+        }
+        CommandKind::Who => Some(who()),
+        CommandKind::Nick => nick(args, connection, send_addr, socket),
+        CommandKind::Me => {
+            send_text(args, server::Style::Action, connection, send_addr, socket);
+            activity.sent();
+            None
+        }
+        CommandKind::Away => {
+            activity.away = true;
+            send_presence(server::Presence::Away, args, connection, send_addr, socket);
+            Some(String::from("You are away, /back when you return"))
+        }
+        CommandKind::Back => {
+            activity.away = false;
+            send_presence(server::Presence::Active, "", connection, send_addr, socket);
+            Some(String::from("You are back"))
+        }
+        CommandKind::Clear => {
+            buf::clear_buf();
+            None
+        }
+        CommandKind::Search => search(args),
+        CommandKind::Notify => Some(notify_level(args)),
+        CommandKind::Export => Some(export(args)),
+        CommandKind::Memo => memo(args, connection, send_addr, socket),
+        CommandKind::Msg => Some(private_message(args, connection, send_addr, socket)),
+        CommandKind::Reply | CommandKind::Edit | CommandKind::Delete | CommandKind::React => {
+            post_command(kind, args, connection, send_addr, socket)
+        }
+        CommandKind::Send => send_file(args),
+        CommandKind::Accept => {
+            transfer::accept(args);
+            None
+        }
+    };
+    if let Some(line) = line {
+        buf::push_message(&line);
+    }
+    buf::print_message();
+    None
+}
+
+/// Run `/nick <id>`, room owner tells everyone once it checked no one has this id. Returns the
+/// line to show if nothing was asked.
+fn nick(
+    args: &str,
+    connection: &Connection,
+    send_addr: &SocketAddr,
+    socket: Arc<UdpSocket>,
+) -> Option<String> {
+    if !protocol::is_valid_id(args) {
+        return Some(buf::paint(server::NICK_INVALID, buf::Tone::Error));
+    }
+    if protocol::get_id().as_deref() == Some(args) {
+        return Some(format!("You are already {}", args));
+    }
+    if !connection.connected {
+        return Some(buf::paint(
+            "Not connected to room owner, id not changed",
+            buf::Tone::Error,
+        ));
+    }
+    sent(server::send_message_to(
+        &server::nick_request(args),
+        send_addr,
+        socket,
+    ))
+}
 
-                buf::print_input(&input_head, &input, input_line);
+/// Run `/search <text>`. Older lines of chat log that have it are put first in message area, then
+/// the last line with it is shown. Returns the line to show if nothing is found.
+fn search(query: &str) -> Option<String> {
+    match chatlog::search(query, buf::first_time()) {
+        Ok(lines) => {
+            for (time, line) in lines {
+                buf::insert_log_line(&buf::paint(&line, buf::Tone::History), time);
             }
         }
+        Err(e) => buf::push_message(&buf::paint(
+            &format!("Chat log can't be searched: {}", e),
+            buf::Tone::Error,
+        )),
+    }
+    if buf::search(query) == 0 {
+        return Some(String::from("Nothing found"));
+    }
+    None
+}
+
+/// Run `/notify [all|mentions|none]`, returns the level of the room or why it is not changed.
+fn notify_level(args: &str) -> String {
+    if !args.is_empty() {
+        match args.parse() {
+            Ok(level) => notify::set_level(level),
+            Err(err) => return buf::paint(&err, buf::Tone::Error),
+        }
     }
+    format!("Notify on {} in this room", notify::level().as_str())
 }
 
-fn get_new_message(mess_que: Arc<Mutex<VecDeque<protocol::Message>>>) -> Option<protocol::Message> {
-    let mut mess_lock = mess_que.lock().unwrap();
-    mess_lock.pop_front()
+/// Notify of a new message or reply of someone else, it is direct when it mentions us.
+fn notify_post(message: &protocol::Message) {
+    let me = protocol::get_id().unwrap_or_default();
+    if message.pro_id.id == me {
+        return;
+    }
+    let text = match server::Code::try_from(message.code) {
+        Ok(server::Code::Message) => server::chat_text(message).0,
+        Ok(server::Code::Answer) => match message.split_about() {
+            Some((_, text)) => text,
+            None => return,
+        },
+        _ => return,
+    };
+    let direct = !notify::mentions(&text, &me).is_empty();
+    notify(&message.pro_id.id, &text, direct);
+}
+
+fn notify(from: &str, text: &str, direct: bool) {
+    if let Err(e) = notify::message(from, text, direct) {
+        buf::push_message(&buf::paint(
+            &format!("Notify command failed: {}", e),
+            buf::Tone::Error,
+        ));
+        buf::print_message();
+    }
 }
 
-fn exit_client(code: i32) {
+/// Line shown when a member goes away or comes back, `was` is what it was doing before.
+fn presence_line(
+    id: &str,
+    was: Option<server::Presence>,
+    presence: server::Presence,
+    status: &str,
+) -> Option<String> {
+    let text = match (was, presence) {
+        (_, server::Presence::Away) if status.is_empty() => String::from("is away"),
+        (_, server::Presence::Away) => format!("is away: {}", status),
+        (Some(server::Presence::Away), server::Presence::Active) => String::from("is back"),
+        _ => return None,
+    };
+    Some(format!(
+        "{} {}",
+        buf::paint_id(id),
+        buf::paint(&text, buf::Tone::Muted)
+    ))
+}
+
+/// Run `/who`, the line listing the members with room owner first.
+fn who() -> String {
+    let members: Vec<String> = roster::members()
+        .iter()
+        .map(|member| {
+            let mut id = buf::paint_id(&member.id);
+            if member.owner {
+                id = format!("@{}", id);
+            }
+            if member.is_away() && !member.status.is_empty() {
+                let away = format!("(away: {})", member.status);
+                id = format!("{} {}", id, buf::paint(&away, buf::Tone::Muted));
+            } else if member.is_away() {
+                id = format!("{} {}", id, buf::paint("(away)", buf::Tone::Muted));
+            } else if member.is_idle() {
+                id = format!("{} {}", id, buf::paint("(idle)", buf::Tone::Muted));
+            }
+            id
+        })
+        .collect();
+    format!("{} in the room: {}", members.len(), members.join(", "))
+}
+
+/// Split `<id> <text>` arguments.
+fn target_and_text(args: &str) -> (&str, &str) {
+    match args.split_once(' ') {
+        Some((target, text)) => (target, text.trim()),
+        None => (args, ""),
+    }
+}
+
+/// Run `/memo <id> <text>`, returns the line to show if it could not be sent.
+fn memo(
+    args: &str,
+    connection: &Connection,
+    send_addr: &SocketAddr,
+    socket: Arc<UdpSocket>,
+) -> Option<String> {
+    let (target, text) = target_and_text(args);
+    if !connection.connected {
+        return Some(buf::paint(
+            "Not connected to room owner, memo not sent",
+            buf::Tone::Error,
+        ));
+    }
+    sent(server::send_message_to(
+        &protocol::Message::new_to(server::Code::Memo as u8, target, text),
+        send_addr,
+        socket,
+    ))
+}
+
+/// Run `/msg <id> <text>`, room owner passes it to `id` only. Returns the line to show.
+fn private_message(
+    args: &str,
+    connection: &Connection,
+    send_addr: &SocketAddr,
+    socket: Arc<UdpSocket>,
+) -> String {
+    let (target, text) = target_and_text(args);
+    if !connection.connected {
+        return buf::paint(
+            "Not connected to room owner, message not sent",
+            buf::Tone::Error,
+        );
+    }
+    if let Some(line) = sent(server::send_message_to(
+        &protocol::Message::new_to(server::Code::Private as u8, target, text),
+        send_addr,
+        socket,
+    )) {
+        return line;
+    }
+    let me = protocol::get_id().unwrap();
+    record(Entry::new(
+        &me,
+        &format!("(private to {}) {}", target, text),
+        EntryKind::Message,
+    ));
+    format!(
+        "{}:\t{} {}",
+        buf::paint_id(&me),
+        buf::paint(&format!("(private to {})", target), buf::Tone::Private),
+        text
+    )
+}
+
+/// Run `/reply <#ref> <text>`, `/edit <text>`, `/delete [#ref]` or `/react <#ref> <emoji>`.
+///
+/// `#ref` is the short uid shown before a message. `/edit` changes our last message and `/delete`
+/// removes it when no reference is given. Returns the line to show if nothing was sent.
+fn post_command(
+    kind: CommandKind,
+    args: &str,
+    connection: &Connection,
+    send_addr: &SocketAddr,
+    socket: Arc<UdpSocket>,
+) -> Option<String> {
+    let me = protocol::get_id().unwrap();
+    let (reference, text) = target_and_text(args);
+    let message = match kind {
+        CommandKind::Reply | CommandKind::React => {
+            let code = if kind == CommandKind::Reply {
+                server::Code::Answer
+            } else {
+                server::Code::React
+            };
+            match posts::find(reference) {
+                Some(target) => protocol::Message::new_about(code as u8, target, text),
+                None => {
+                    return Some(buf::paint(
+                        &format!("No message #{}", reference),
+                        buf::Tone::Error,
+                    ))
+                }
+            }
+        }
+        CommandKind::Edit => match posts::last_of(&me) {
+            Some(target) => protocol::Message::new_about(server::Code::Edit as u8, target, args),
+            None => return Some(String::from("No message of yours to edit")),
+        },
+        _ => {
+            let target = if args.is_empty() {
+                posts::last_of(&me)
+            } else {
+                posts::find(args)
+                    .filter(|uid| posts::get(*uid).is_some_and(|post| post.author == me))
+            };
+            match target {
+                Some(target) => {
+                    protocol::Message::new_about(server::Code::Delete as u8, target, "")
+                }
+                None => return Some(String::from("No message of yours to delete")),
+            }
+        }
+    };
+    if !connection.connected {
+        return Some(buf::paint(
+            "Not connected to room owner, nothing sent",
+            buf::Tone::Error,
+        ));
+    }
+    sent(server::send_message_to(&message, send_addr, socket))
+}
+
+/// The line to show if a frame could not be sent.
+fn sent(result: io::Result<()>) -> Option<String> {
+    result
+        .err()
+        .map(|e| buf::paint(&format!("Not sent: {}", e), buf::Tone::Error))
+}
+
+/// Write the entry to the chat log, telling if logging had to stop.
+fn record(entry: Entry) {
+    if let Err(e) = chatlog::record(entry) {
+        buf::push_message(&buf::paint(
+            &format!("Chat log stopped: {}", e),
+            buf::Tone::Error,
+        ));
+        buf::print_message();
+    }
+}
+
+/// Show a chat message, or show again the message it changes. Returns its chat log entry, `None`
+/// if it changed nothing.
+fn show_post(message: &protocol::Message, history: bool) -> Option<Entry> {
+    let uid = posts::apply(message, history)?;
+    let post = posts::get(uid)?;
+    if uid == message.uid {
+        buf::insert_message(&posts::render(&post), Some(uid), post.clock, post.time);
+    } else {
+        buf::replace_message(uid, &posts::render(&post));
+        for reply in posts::replies_to(uid).into_iter().filter_map(posts::get) {
+            buf::replace_message(reply.uid, &posts::render(&reply));
+        }
+    }
+    let kind = if history {
+        EntryKind::History
+    } else {
+        EntryKind::Message
+    };
+    Some(Entry {
+        text: posts::describe(message),
+        ..Entry::of_message(message, kind)
+    })
+}
+
+/// Run `/send [@id] <path>`, offering the file to `id` or the whole room. Returns the usage if
+/// there is no path.
+fn send_file(args: &str) -> Option<String> {
+    let (to, path) = match args.strip_prefix('@').and_then(|args| args.split_once(' ')) {
+        Some((to, path)) => (Some(to), path.trim()),
+        None => (None, args),
+    };
+    if path.is_empty() {
+        return Some(command::get(CommandKind::Send).usage());
+    }
+    transfer::send(std::path::Path::new(path), to);
+    None
+}
+
+/// Show what happened to a file transfer, on the line of the transfer when it is still shown.
+fn show_transfer(id: u64, name: &str, state: &TransferState) {
+    let line = match state {
+        TransferState::Offered { from, size } => format!(
+            "{}:\t{} {} ({}), {} to save it",
+            buf::paint_id(from),
+            buf::paint("(file)", buf::Tone::System),
+            name,
+            transfer::format_size(*size),
+            buf::paint(
+                &format!("/accept {}", protocol::short_uid(id)),
+                buf::Tone::Strong
+            )
+        ),
+        TransferState::Sending { to, size } => format!(
+            "Offering {} ({}) to {}",
+            name,
+            transfer::format_size(*size),
+            if to.is_empty() { "the room" } else { to }
+        ),
+        TransferState::Receiving { received, size } => {
+            format!("Receiving {} {}%", name, received * 100 / (*size).max(1))
+        }
+        TransferState::Received { path } => buf::paint(
+            &format!("Saved {} to {}", name, path.display()),
+            buf::Tone::Success,
+        ),
+        TransferState::Delivered { by, ok: true } => format!("{} received {}", by, name),
+        TransferState::Delivered { by, ok: false } => buf::paint(
+            &format!("{} got a broken copy of {}", by, name),
+            buf::Tone::Error,
+        ),
+        TransferState::Failed(reason) => buf::paint(
+            &format!("Transfer of {} failed: {}", name, reason),
+            buf::Tone::Error,
+        ),
+    };
+    match state {
+        TransferState::Offered { .. } | TransferState::Sending { .. } => {
+            buf::insert_message(&line, Some(id), protocol::clock(), protocol::now_millis())
+        }
+        TransferState::Delivered { .. } => buf::push_message(&line),
+        _ => {
+            if !buf::replace_message(id, &line) {
+                buf::push_message(&line);
+            }
+        }
+    }
+}
+
+/// Run `/export [txt|jsonl|md] [path]`, returns the line telling how it went.
+fn export(args: &str) -> String {
+    let mut args = args.split_whitespace();
+    let format = match args.next().unwrap_or("txt").parse::<ExportFormat>() {
+        Ok(format) => format,
+        Err(err) => return buf::paint(&err, buf::Tone::Error),
+    };
+    let path = args.next().map(std::path::Path::new);
+    match chatlog::export(format, path) {
+        Ok(path) => format!("Exported to {}", path.display()),
+        Err(e) => buf::paint(&format!("Export failed: {}", e), buf::Tone::Error),
+    }
+}
+
+/// Entry kept in chat log for the event, `None` if it is not part of the conversation.
+fn event_entry(event: &RoomEvent) -> Option<Entry> {
+    match event {
+        RoomEvent::MemberJoined { id } => Some(Entry::new(id, "joined", EntryKind::System)),
+        RoomEvent::MemberLeft { id } => Some(Entry::new(id, "left", EntryKind::System)),
+        RoomEvent::RoomClosed { id } => Some(Entry::new(id, "closed the room", EntryKind::System)),
+        RoomEvent::MemberRenamed { old, new } => Some(Entry::new(
+            old,
+            &format!("is now known as {}", new),
+            EntryKind::System,
+        )),
+        RoomEvent::Memo { from, text } => Some(Entry::new(
+            from,
+            &format!("(memo) {}", text),
+            EntryKind::Message,
+        )),
+        RoomEvent::Private { from, text } => Some(Entry::new(
+            from,
+            &format!("(private) {}", text),
+            EntryKind::Message,
+        )),
+        RoomEvent::TransferStatus {
+            name,
+            state: TransferState::Offered { from, size },
+            ..
+        } => Some(Entry::new(
+            from,
+            &format!(
+                "offered the file {} ({})",
+                name,
+                transfer::format_size(*size)
+            ),
+            EntryKind::System,
+        )),
+        _ => None,
+    }
+}
+
+/// Line shown in message area for the event, `None` if it is not shown.
+fn format_event(event: &RoomEvent) -> Option<String> {
+    match event {
+        RoomEvent::MemberJoined { id } => Some(format!(
+            "{}:\t{}",
+            buf::paint_id(id),
+            buf::paint(server::JOIN_SUCCESS, buf::Tone::System)
+        )),
+        RoomEvent::MemberLeft { id } => Some(format!(
+            "{}:\t{}",
+            buf::paint_id(id),
+            buf::paint(server::EXIT_ROOM, buf::Tone::System)
+        )),
+        RoomEvent::RoomClosed { id } => Some(format!(
+            "{}:\t{}",
+            buf::paint_id(id),
+            buf::paint(server::ROOM_CLOSED, buf::Tone::System)
+        )),
+        RoomEvent::MemberRenamed { old, new } => Some(format!(
+            "{} {} {}",
+            buf::paint_id(old),
+            buf::paint("is now known as", buf::Tone::System),
+            buf::paint_id(new)
+        )),
+        RoomEvent::Memo { from, text } => Some(format!(
+            "{}:\t{} {}",
+            buf::paint_id(from),
+            buf::paint("(memo)", buf::Tone::Memo),
+            text
+        )),
+        RoomEvent::Private { from, text } => Some(format!(
+            "{}:\t{} {}",
+            buf::paint_id(from),
+            buf::paint("(private)", buf::Tone::Private),
+            text
+        )),
+        RoomEvent::MemoReceipt { to, status } => Some(match status {
+            MemoStatus::Delivered => format!("Memo to {} delivered", to),
+            MemoStatus::Queued => format!("{} is offline, memo kept by room owner", to),
+            MemoStatus::Unknown => buf::paint(
+                &format!("{} has never been in this room", to),
+                buf::Tone::Error,
+            ),
+            MemoStatus::Full => buf::paint("Room owner can't keep more memos", buf::Tone::Error),
+        }),
+        RoomEvent::Error(err) => Some(buf::paint(err.as_str(), buf::Tone::Error)),
+        // Chat messages are shown by `show_post`
+        RoomEvent::ChatMessage(_) | RoomEvent::History(_) => None,
+        // Transfers are shown by `show_transfer`
+        RoomEvent::Transfer(_) | RoomEvent::TransferStatus { .. } => None,
+        RoomEvent::JoinResult(_) | RoomEvent::LeaveAck | RoomEvent::Heartbeat => None,
+        // Members already in the room are only listed beside message area
+        RoomEvent::MemberPresent { .. } => None,
+        // Shown by `presence_line`, only when it goes away or comes back
+        RoomEvent::Presence { .. } => None,
+    }
+}
+
+/// Leave the room, stop the receive thread, restore the terminal and exit.
+///
+/// Ctrl+C, SIGTERM and SIGHUP come here too, all with `INTERRUPTED_CODE`. Only the first caller
+/// does the work, others just wait for the exit.
+pub fn shutdown(code: i32) -> ! {
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        loop {
+            thread::park();
+        }
+    }
+
+    let session = SESSION.lock().unwrap().take();
+    if let Some(mut session) = session {
+        if let Some(send_addr) = session.send_addr {
+            let leave_acks = session.bus.subscribe(&[EventKind::LeaveAck]);
+            let _ = server::send_message_to(
+                &server::exit_request(),
+                &send_addr,
+                session.socket.clone(),
+            );
+            let _ = leave_acks.recv_timeout(LEAVE_TIMEOUT);
+        }
+        server::stop();
+        if let Some(receive_thread) = session.receive_thread.take() {
+            let _ = receive_thread.join();
+        }
+        if let Some(transfer_thread) = session.transfer_thread.take() {
+            let _ = transfer_thread.join();
+        }
+    }
+
+    exit_client(code)
+}
+
+fn exit_client(code: i32) -> ! {
     buf::reset();
     let mut stdout = io::stdout();
     queue!(stdout, cursor::Show).unwrap();
+    stdout.flush().unwrap();
+    let _ = terminal::disable_raw_mode();
     exit(code);
-}
\ No newline at end of file
+}
@@ -0,0 +1,361 @@
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum CommandKind {
+    Help,
+    Quit,
+    Who,
+    Nick,
+    Me,
+    Away,
+    Back,
+    Clear,
+    Search,
+    Notify,
+    Export,
+    Memo,
+    Msg,
+    Reply,
+    Edit,
+    Delete,
+    React,
+    Send,
+    Accept,
+}
+
+/// A command typed in the chat input, like `/memo bob see me`.
+pub struct Command {
+    pub kind: CommandKind,
+    pub name: &'static str,
+    /// Arguments, as shown by `/help`
+    pub args: &'static str,
+    /// Fewest words of arguments the command needs
+    pub min_args: usize,
+    pub help: &'static str,
+}
+
+impl Command {
+    /// Name with its arguments, like `/memo <id> <text>`.
+    pub fn signature(&self) -> String {
+        if self.args.is_empty() {
+            self.name.to_string()
+        } else {
+            format!("{} {}", self.name, self.args)
+        }
+    }
+
+    pub fn usage(&self) -> String {
+        format!("Usage: {}", self.signature())
+    }
+}
+
+/// Every command, in the order `/help` shows them.
+pub const COMMANDS: &[Command] = &[
+    Command {
+        kind: CommandKind::Help,
+        name: "/help",
+        args: "[command]",
+        min_args: 0,
+        help: "show the commands, or how to use one",
+    },
+    Command {
+        kind: CommandKind::Quit,
+        name: "/quit",
+        args: "",
+        min_args: 0,
+        help: "leave the room and quit",
+    },
+    Command {
+        kind: CommandKind::Who,
+        name: "/who",
+        args: "",
+        min_args: 0,
+        help: "list the members of the room",
+    },
+    Command {
+        kind: CommandKind::Nick,
+        name: "/nick",
+        args: "<id>",
+        min_args: 1,
+        help: "change your id",
+    },
+    Command {
+        kind: CommandKind::Me,
+        name: "/me",
+        args: "<action>",
+        min_args: 1,
+        help: "tell the room what you are doing",
+    },
+    Command {
+        kind: CommandKind::Away,
+        name: "/away",
+        args: "[reason]",
+        min_args: 0,
+        help: "tell the room you are away",
+    },
+    Command {
+        kind: CommandKind::Back,
+        name: "/back",
+        args: "",
+        min_args: 0,
+        help: "tell the room you are back",
+    },
+    Command {
+        kind: CommandKind::Clear,
+        name: "/clear",
+        args: "",
+        min_args: 0,
+        help: "clear message area",
+    },
+    Command {
+        kind: CommandKind::Search,
+        name: "/search",
+        args: "<text>",
+        min_args: 1,
+        help: "find a text in message area and chat log, also Ctrl+F",
+    },
+    Command {
+        kind: CommandKind::Notify,
+        name: "/notify",
+        args: "[all|mentions|none]",
+        min_args: 0,
+        help: "show or change which messages notify you in this room",
+    },
+    Command {
+        kind: CommandKind::Msg,
+        name: "/msg",
+        args: "<id> <text>",
+        min_args: 2,
+        help: "send a message only id can see",
+    },
+    Command {
+        kind: CommandKind::Memo,
+        name: "/memo",
+        args: "<id> <text>",
+        min_args: 2,
+        help: "leave a memo, kept by room owner while id is offline",
+    },
+    Command {
+        kind: CommandKind::Reply,
+        name: "/reply",
+        args: "<#ref> <text>",
+        min_args: 2,
+        help: "reply to a message",
+    },
+    Command {
+        kind: CommandKind::React,
+        name: "/react",
+        args: "<#ref> <emoji>",
+        min_args: 2,
+        help: "react to a message",
+    },
+    Command {
+        kind: CommandKind::Edit,
+        name: "/edit",
+        args: "<text>",
+        min_args: 1,
+        help: "change your last message",
+    },
+    Command {
+        kind: CommandKind::Delete,
+        name: "/delete",
+        args: "[#ref]",
+        min_args: 0,
+        help: "delete your last message, or the one given",
+    },
+    Command {
+        kind: CommandKind::Send,
+        name: "/send",
+        args: "[@id] <path>",
+        min_args: 1,
+        help: "offer a file to the room, or to one member",
+    },
+    Command {
+        kind: CommandKind::Accept,
+        name: "/accept",
+        args: "<#ref>",
+        min_args: 1,
+        help: "save a file offered to you",
+    },
+    Command {
+        kind: CommandKind::Export,
+        name: "/export",
+        args: "[txt|jsonl|md] [path]",
+        min_args: 0,
+        help: "write the conversation since you joined",
+    },
+];
+
+/// What was typed in the chat input.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Input<'a> {
+    /// Text to send to the room
+    Text(&'a str),
+    /// A command with its arguments, trimmed
+    Command(CommandKind, &'a str),
+}
+
+pub fn get(kind: CommandKind) -> &'static Command {
+    COMMANDS
+        .iter()
+        .find(|command| command.kind == kind)
+        .unwrap()
+}
+
+/// Command named `name`, with or without its `/`.
+pub fn find(name: &str) -> Option<&'static Command> {
+    let name = name.strip_prefix('/').unwrap_or(name);
+    COMMANDS.iter().find(|command| &command.name[1..] == name)
+}
+
+/// Tell a command from text. `//` at the start sends the text with a single `/`.
+///
+/// Returns what to show when the command is unknown or misses arguments.
+pub fn parse(input: &str) -> Result<Input<'_>, String> {
+    if input.starts_with("//") {
+        return Ok(Input::Text(&input[1..]));
+    }
+    if !input.starts_with('/') {
+        return Ok(Input::Text(input));
+    }
+    let (name, args) = match input.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (input, ""),
+    };
+    let command = find(name)
+        .ok_or_else(|| format!("Unknown command {}, type /help to see the commands", name))?;
+    if args.split_whitespace().count() < command.min_args {
+        return Err(command.usage());
+    }
+    Ok(Input::Command(command.kind, args))
+}
+
+/// Lines of `/help`: every command, or how to use the one named.
+pub fn help(name: &str) -> Vec<String> {
+    if !name.is_empty() {
+        return match find(name) {
+            Some(command) => vec![command.usage(), command.help.to_string()],
+            None => vec![format!("Unknown command {}", name)],
+        };
+    }
+    let mut lines = vec![String::from(
+        "Commands, start a message with // to send a /:",
+    )];
+    lines.extend(
+        COMMANDS
+            .iter()
+            .map(|command| format!("  {} - {}", command.signature(), command.help)),
+    );
+    lines
+}
+
+/// What `word` may be completed to: a command name at the start of the input, else one of the
+/// member `ids`, with its `@` if typed.
+pub fn completions(word: &str, first: bool, ids: &[String]) -> Vec<String> {
+    if first && word.starts_with('/') {
+        return COMMANDS
+            .iter()
+            .filter(|command| command.name.starts_with(word))
+            .map(|command| command.name.to_string())
+            .collect();
+    }
+    let (mark, start) = match word.strip_prefix('@') {
+        Some(start) => ("@", start),
+        None => ("", word),
+    };
+    ids.iter()
+        .filter(|id| id.starts_with(start))
+        .map(|id| format!("{}{}", mark, id))
+        .collect()
+}
+
+/// Longest start shared by all `words`.
+pub fn common_prefix(words: &[String]) -> String {
+    let first = match words.first() {
+        Some(first) => first,
+        None => return String::new(),
+    };
+    let mut len = first.len();
+    for word in &words[1..] {
+        len = first
+            .char_indices()
+            .zip(word.chars())
+            .take_while(|((_, a), b)| a == b)
+            .map(|((index, a), _)| index + a.len_utf8())
+            .last()
+            .unwrap_or(0)
+            .min(len);
+    }
+    first[..len].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn commands_are_told_from_text() {
+        assert_eq!(parse("hello"), Ok(Input::Text("hello")));
+        assert_eq!(parse("//me waves"), Ok(Input::Text("/me waves")));
+        assert_eq!(parse("/quit"), Ok(Input::Command(CommandKind::Quit, "")));
+        assert_eq!(
+            parse("/me   waves at bob "),
+            Ok(Input::Command(CommandKind::Me, "waves at bob"))
+        );
+        assert_eq!(
+            parse("/memo bob see me"),
+            Ok(Input::Command(CommandKind::Memo, "bob see me"))
+        );
+    }
+
+    #[test]
+    fn unknown_commands_and_missing_arguments_are_refused() {
+        assert!(parse("/dance")
+            .unwrap_err()
+            .starts_with("Unknown command /dance"));
+        assert_eq!(parse("/me"), Err(get(CommandKind::Me).usage()));
+        assert_eq!(
+            parse("/msg bob"),
+            Err(String::from("Usage: /msg <id> <text>"))
+        );
+        // Names are whole words
+        assert!(parse("/quitnow").is_err());
+    }
+
+    #[test]
+    fn command_names_complete_at_the_start() {
+        assert_eq!(completions("/me", true, &[]), words(&["/me", "/memo"]));
+        assert_eq!(completions("/q", true, &[]), words(&["/quit"]));
+        assert!(completions("/zz", true, &[]).is_empty());
+        assert_eq!(common_prefix(&words(&["/me", "/memo"])), "/me");
+        assert_eq!(common_prefix(&words(&["你好", "你们"])), "你");
+        assert_eq!(common_prefix(&words(&["bob", "carol"])), "");
+        assert_eq!(common_prefix(&[]), "");
+    }
+
+    #[test]
+    fn member_ids_complete_elsewhere() {
+        let ids = words(&["alice", "albert", "bob"]);
+        assert_eq!(completions("al", false, &ids), words(&["alice", "albert"]));
+        assert_eq!(completions("@b", true, &ids), words(&["@bob"]));
+        assert_eq!(completions("/me", false, &ids), Vec::<String>::new());
+    }
+
+    #[test]
+    fn every_command_is_found_and_has_help() {
+        for command in COMMANDS {
+            assert_eq!(
+                find(command.name).map(|found| found.kind),
+                Some(command.kind)
+            );
+            assert_eq!(
+                find(&command.name[1..]).map(|found| found.kind),
+                Some(command.kind)
+            );
+            assert_eq!(help(command.name)[0], command.usage());
+        }
+        assert_eq!(help("").len(), COMMANDS.len() + 1);
+    }
+}
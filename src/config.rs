@@ -0,0 +1,299 @@
+use std::{convert::TryFrom, fs, io, path::PathBuf, str::FromStr, sync::Mutex, time::Duration};
+
+use crossterm::style::Color;
+
+/// Config file read when `MOYU_CONFIG` is not set.
+pub const DEFAULT_CONFIG_PATH: &str = "moyu.conf";
+
+/// Settings read from the config file, every key is optional.
+///
+/// The file is made of `key = value` lines, `#` starts a comment.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// `history.count`: how many messages room owner keeps to replay to new members
+    pub history_count: usize,
+    /// `history.age`: seconds after which a message is no longer replayed, `0` to keep it
+    pub history_age: Option<Duration>,
+    /// `log.enabled`: keep a chat log of every room on disk
+    pub log_enabled: bool,
+    /// `log.dir`: directory holding a sub directory of logs per room
+    pub log_dir: PathBuf,
+    /// `log.rotate`: start a new log file every day or when it grows too big
+    pub log_rotate: LogRotate,
+    /// `log.max_size`: size in bytes of a log file before rotating, with `log.rotate = size`
+    pub log_max_size: u64,
+    /// `memo.count`: how many memos room owner keeps for offline members
+    pub memo_count: usize,
+    /// `memo.per_member`: how many memos may wait for the same member
+    pub memo_per_member: usize,
+    /// `memo.age`: seconds after which an undelivered memo is dropped, `0` to keep it
+    pub memo_age: Option<Duration>,
+    /// `transfer.dir`: directory where accepted files are saved
+    pub transfer_dir: PathBuf,
+    /// `room.port`: port room owner listens on, `0` for a new one every start
+    pub room_port: u16,
+    /// `scrollback.lines`: lines kept in message area to scroll back to, `0` to keep them all
+    pub scrollback: usize,
+    /// `theme.*`: colors of the chat screen
+    pub theme: Theme,
+    /// `notify.level`: messages that ring the bell and run `notify.command`
+    pub notify_level: NotifyLevel,
+    /// `notify.room.<room address>`: `notify.level` of one room
+    pub notify_rooms: Vec<(String, NotifyLevel)>,
+    /// `notify.bell`: ring the terminal bell
+    pub notify_bell: bool,
+    /// `notify.command`: command run with the message as its last argument, like `notify-send`
+    pub notify_command: Option<String>,
+}
+
+/// Which messages notify you. Private messages and memos count as mentions.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum NotifyLevel {
+    All,
+    Mentions,
+    None,
+}
+
+impl NotifyLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotifyLevel::All => "all",
+            NotifyLevel::Mentions => "mentions",
+            NotifyLevel::None => "none",
+        }
+    }
+}
+
+impl FromStr for NotifyLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(NotifyLevel::All),
+            "mentions" => Ok(NotifyLevel::Mentions),
+            "none" => Ok(NotifyLevel::None),
+            _ => Err(format!(
+                "Unknown notify level `{}`, use all, mentions or none",
+                s
+            )),
+        }
+    }
+}
+
+/// Colors of the chat screen, each key takes a color name like `dark_cyan`.
+#[derive(Clone, Debug)]
+pub struct Theme {
+    /// `theme.colors`: `auto` uses colors unless `NO_COLOR` is set or `TERM` is `dumb`
+    pub colors: ColorMode,
+    /// `theme.system`: joins, leaves and the end of the room
+    pub system: Color,
+    /// `theme.error`
+    pub error: Color,
+    /// `theme.success`
+    pub success: Color,
+    /// `theme.history`: messages replayed by room owner
+    pub history: Color,
+    /// `theme.muted`: quotes, edits, deletes and other notes
+    pub muted: Color,
+    /// `theme.private`
+    pub private: Color,
+    /// `theme.memo`
+    pub memo: Color,
+    /// `theme.mention`: your id written by others
+    pub mention: Color,
+    /// `theme.names`: colors of ids, separated by `,`. An id always gets the same one.
+    pub names: Vec<Color>,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            colors: ColorMode::Auto,
+            system: Color::Cyan,
+            error: Color::Red,
+            success: Color::Green,
+            history: Color::DarkGrey,
+            muted: Color::DarkGrey,
+            private: Color::Magenta,
+            memo: Color::Yellow,
+            mention: Color::Yellow,
+            names: vec![
+                Color::Green,
+                Color::Yellow,
+                Color::Blue,
+                Color::Magenta,
+                Color::DarkGreen,
+                Color::DarkYellow,
+                Color::DarkMagenta,
+                Color::DarkRed,
+            ],
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum LogRotate {
+    Daily,
+    Size,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            history_count: 50,
+            history_age: Some(Duration::from_secs(60 * 60)),
+            log_enabled: false,
+            log_dir: PathBuf::from("logs"),
+            log_rotate: LogRotate::Daily,
+            log_max_size: 1024 * 1024,
+            memo_count: 100,
+            memo_per_member: 20,
+            memo_age: Some(Duration::from_secs(24 * 60 * 60)),
+            transfer_dir: PathBuf::from("downloads"),
+            room_port: 8080,
+            scrollback: 1000,
+            theme: Theme::default(),
+            notify_level: NotifyLevel::Mentions,
+            notify_rooms: Vec::new(),
+            notify_bell: true,
+            notify_command: None,
+        }
+    }
+}
+
+lazy_static! {
+    static ref CONFIG: Mutex<Config> = Mutex::new(Config::default());
+}
+
+pub fn get() -> Config {
+    CONFIG.lock().unwrap().clone()
+}
+
+/// Read the config file. A missing file keeps the default settings.
+pub fn load() -> io::Result<()> {
+    let path = std::env::var("MOYU_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let mut config = Config::default();
+    for (index, line) in content.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(start) => &line[..start],
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        let result = match line.split_once('=') {
+            Some((key, value)) => apply(&mut config, key.trim(), value.trim()),
+            None => Err(String::from("expected `key = value`")),
+        };
+        if let Err(err) = result {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", path, index + 1, err),
+            ));
+        }
+    }
+
+    *CONFIG.lock().unwrap() = config;
+    Ok(())
+}
+
+fn apply(config: &mut Config, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "history.count" => config.history_count = parse_number(key, value)?,
+        "history.age" => config.history_age = parse_age(key, value)?,
+        "log.enabled" => config.log_enabled = parse_bool(key, value)?,
+        "log.dir" => config.log_dir = PathBuf::from(value),
+        "log.rotate" => {
+            config.log_rotate = match value {
+                "daily" => LogRotate::Daily,
+                "size" => LogRotate::Size,
+                _ => return Err(format!("`{}` expects `daily` or `size`", key)),
+            }
+        }
+        "log.max_size" => config.log_max_size = parse_number(key, value)? as u64,
+        "memo.count" => config.memo_count = parse_number(key, value)?,
+        "memo.per_member" => config.memo_per_member = parse_number(key, value)?,
+        "memo.age" => config.memo_age = parse_age(key, value)?,
+        "transfer.dir" => config.transfer_dir = PathBuf::from(value),
+        "room.port" => {
+            config.room_port = value
+                .parse()
+                .map_err(|_| format!("`{}` expects a port, got `{}`", key, value))?
+        }
+        "scrollback.lines" => config.scrollback = parse_number(key, value)?,
+        "theme.colors" => {
+            config.theme.colors = match value {
+                "auto" => ColorMode::Auto,
+                "always" => ColorMode::Always,
+                "never" => ColorMode::Never,
+                _ => return Err(format!("`{}` expects `auto`, `always` or `never`", key)),
+            }
+        }
+        "theme.system" => config.theme.system = parse_color(key, value)?,
+        "theme.error" => config.theme.error = parse_color(key, value)?,
+        "theme.success" => config.theme.success = parse_color(key, value)?,
+        "theme.history" => config.theme.history = parse_color(key, value)?,
+        "theme.muted" => config.theme.muted = parse_color(key, value)?,
+        "theme.private" => config.theme.private = parse_color(key, value)?,
+        "theme.memo" => config.theme.memo = parse_color(key, value)?,
+        "theme.mention" => config.theme.mention = parse_color(key, value)?,
+        "theme.names" => {
+            config.theme.names = value
+                .split(',')
+                .map(|color| parse_color(key, color.trim()))
+                .collect::<Result<_, _>>()?
+        }
+        "notify.level" => config.notify_level = value.parse()?,
+        "notify.bell" => config.notify_bell = parse_bool(key, value)?,
+        "notify.command" => {
+            config.notify_command = Some(value.to_string()).filter(|command| !command.is_empty())
+        }
+        _ => match key.strip_prefix("notify.room.") {
+            Some(room) if !room.is_empty() => {
+                config.notify_rooms.push((room.to_string(), value.parse()?))
+            }
+            _ => return Err(format!("unknown key `{}`", key)),
+        },
+    }
+    Ok(())
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("`{}` expects true or false, got `{}`", key, value)),
+    }
+}
+
+fn parse_color(key: &str, value: &str) -> Result<Color, String> {
+    Color::try_from(value).map_err(|_| format!("`{}` expects a color name, got `{}`", key, value))
+}
+
+/// Seconds, `0` meaning no limit.
+fn parse_age(key: &str, value: &str) -> Result<Option<Duration>, String> {
+    Ok(match parse_number(key, value)? {
+        0 => None,
+        secs => Some(Duration::from_secs(secs as u64)),
+    })
+}
+
+fn parse_number(key: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("`{}` expects a number, got `{}`", key, value))
+}
@@ -0,0 +1,222 @@
+use std::sync::{
+    mpsc::{self, Receiver, SyncSender, TrySendError},
+    Mutex,
+};
+
+use crate::prelude::*;
+use memo::MemoStatus;
+use transfer::TransferState;
+
+/// How many events a subscriber may have pending before new ones are dropped for it. Room owner
+/// replays history to a joiner all at once, it must fit between two draws of the chat screen.
+pub const EVENT_BUF: usize = 256;
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum JoinResult {
+    /// Joined the room of `owner`, which has `members` members with us.
+    Success {
+        owner: String,
+        members: usize,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+/// Events produced by the receive thread.
+#[derive(Clone, Debug)]
+pub enum Event {
+    JoinResult(JoinResult),
+    /// A message, or a reply, edit, delete or reaction, see `server::Code`.
+    ChatMessage(protocol::Message),
+    /// Chat message sent before we joined, replayed by room owner.
+    History(protocol::Message),
+    MemberJoined {
+        id: String,
+    },
+    MemberLeft {
+        id: String,
+    },
+    /// Member already in the room when we joined.
+    MemberPresent {
+        id: String,
+    },
+    /// What a member is doing, `status` is the reason it is away.
+    Presence {
+        id: String,
+        presence: server::Presence,
+        status: String,
+    },
+    /// A member, maybe us, changed its id.
+    MemberRenamed {
+        old: String,
+        new: String,
+    },
+    /// The room owner has taken note of our leave.
+    LeaveAck,
+    /// The room owner has left, so the room no longer exists.
+    RoomClosed {
+        id: String,
+    },
+    /// The room owner answered our heartbeat.
+    Heartbeat,
+    /// Memo left for us, maybe while we were offline.
+    Memo {
+        from: String,
+        text: String,
+    },
+    /// Message sent only to us.
+    Private {
+        from: String,
+        text: String,
+    },
+    /// What happened to the memo we left for `to`.
+    MemoReceipt {
+        to: String,
+        status: MemoStatus,
+    },
+    /// File transfer frame, handled by the transfer thread.
+    Transfer(protocol::Message),
+    /// Something happened to the file transfer `id`, for the UI.
+    TransferStatus {
+        id: u64,
+        name: String,
+        state: TransferState,
+    },
+    Error(String),
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum EventKind {
+    JoinResult,
+    ChatMessage,
+    History,
+    MemberJoined,
+    MemberLeft,
+    MemberPresent,
+    MemberRenamed,
+    Presence,
+    LeaveAck,
+    RoomClosed,
+    Heartbeat,
+    Memo,
+    MemoReceipt,
+    Private,
+    Transfer,
+    TransferStatus,
+    Error,
+}
+
+impl Event {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            Event::JoinResult(_) => EventKind::JoinResult,
+            Event::ChatMessage(_) => EventKind::ChatMessage,
+            Event::History(_) => EventKind::History,
+            Event::MemberJoined { .. } => EventKind::MemberJoined,
+            Event::MemberLeft { .. } => EventKind::MemberLeft,
+            Event::MemberPresent { .. } => EventKind::MemberPresent,
+            Event::MemberRenamed { .. } => EventKind::MemberRenamed,
+            Event::Presence { .. } => EventKind::Presence,
+            Event::LeaveAck => EventKind::LeaveAck,
+            Event::RoomClosed { .. } => EventKind::RoomClosed,
+            Event::Heartbeat => EventKind::Heartbeat,
+            Event::Memo { .. } => EventKind::Memo,
+            Event::MemoReceipt { .. } => EventKind::MemoReceipt,
+            Event::Private { .. } => EventKind::Private,
+            Event::Transfer(_) => EventKind::Transfer,
+            Event::TransferStatus { .. } => EventKind::TransferStatus,
+            Event::Error(_) => EventKind::Error,
+        }
+    }
+}
+
+struct Subscriber {
+    kinds: Vec<EventKind>,
+    sender: SyncSender<Event>,
+    /// Events dropped since its channel was last found full
+    missed: usize,
+}
+
+/// Fan out events to every subscriber interested in their kind.
+///
+/// Each subscriber owns a bounded channel. Publishing never waits: the receive thread must keep
+/// going while the UI sits in a prompt or waits for it to stop, so a subscriber that falls behind
+/// misses events, and gets an `Error` telling how many once it catches up. Dropping the receiver
+/// ends the subscription.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    pub fn subscribe(&self, kinds: &[EventKind]) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::sync_channel(EVENT_BUF);
+        let mut lock_subscribers = self.subscribers.lock().unwrap();
+        lock_subscribers.push(Subscriber {
+            kinds: kinds.to_vec(),
+            sender,
+            missed: 0,
+        });
+        receiver
+    }
+
+    pub fn publish(&self, event: Event) {
+        let kind = event.kind();
+        let mut lock_subscribers = self.subscribers.lock().unwrap();
+        lock_subscribers.retain_mut(|subscriber| {
+            if !subscriber.kinds.contains(&kind) {
+                return true;
+            }
+            match subscriber.sender.try_send(event.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    subscriber.missed += 1;
+                    return true;
+                }
+                Err(TrySendError::Disconnected(_)) => return false,
+            }
+            if subscriber.missed > 0 && subscriber.kinds.contains(&EventKind::Error) {
+                let missed = format!("{} events were missed while busy", subscriber.missed);
+                if subscriber.sender.try_send(Event::Error(missed)).is_ok() {
+                    subscriber.missed = 0;
+                }
+            }
+            true
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish_does_not_wait_for_a_full_subscriber() {
+        let bus = EventBus::new();
+        let events = bus.subscribe(&[EventKind::Heartbeat, EventKind::Error]);
+        for _ in 0..EVENT_BUF + 3 {
+            bus.publish(Event::Heartbeat);
+        }
+        assert_eq!(events.try_iter().count(), EVENT_BUF);
+
+        bus.publish(Event::Heartbeat);
+        assert!(matches!(events.try_recv(), Ok(Event::Heartbeat)));
+        match events.try_recv() {
+            Ok(Event::Error(missed)) => assert!(missed.starts_with("3 ")),
+            other => panic!("expected the missed events, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dropped_receiver_ends_subscription() {
+        let bus = EventBus::new();
+        drop(bus.subscribe(&[EventKind::Heartbeat]));
+        bus.publish(Event::Heartbeat);
+        assert!(bus.subscribers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,225 @@
+use std::{collections::VecDeque, convert::TryFrom, time::Instant};
+
+use crate::prelude::*;
+use server::Code;
+
+/// Recent chat messages that room owner replays to new members.
+///
+/// Bounded by `history.count` and `history.age` of the config.
+#[derive(Default)]
+pub struct History {
+    messages: VecDeque<(Instant, protocol::Message)>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History::default()
+    }
+
+    /// Keep a chat message. An edit changes the message it is about, a delete removes it along
+    /// with the replies and reactions to it.
+    pub fn push(&mut self, message: &protocol::Message) {
+        match (Code::try_from(message.code), message.split_about()) {
+            (Ok(Code::Edit), Some((target, text))) => {
+                if let Some((_, edited)) = self
+                    .messages
+                    .iter_mut()
+                    .find(|(_, kept)| kept.uid == target && kept.pro_id.id == message.pro_id.id)
+                {
+                    edited.payload = match edited.split_about() {
+                        _ if edited.code == Code::Message as u8 => {
+                            let (_, style) = server::chat_text(edited);
+                            server::chat_message(&text, style).payload
+                        }
+                        Some((reply_to, _)) => {
+                            protocol::Message::new_about(edited.code, reply_to, &text).payload
+                        }
+                        None => protocol::Payload::Text(text),
+                    };
+                    return;
+                }
+            }
+            (Ok(Code::Delete), Some((target, _))) => {
+                let is_author = self
+                    .messages
+                    .iter()
+                    .any(|(_, kept)| kept.uid == target && kept.pro_id.id == message.pro_id.id);
+                if is_author {
+                    self.messages.retain(|(_, kept)| {
+                        kept.uid != target
+                            && !matches!(kept.split_about(), Some((about, _)) if about == target)
+                    });
+                    return;
+                }
+            }
+            _ => {}
+        }
+        self.messages.push_back((Instant::now(), message.clone()));
+        self.prune();
+    }
+
+    /// Messages still in history, oldest first.
+    pub fn recent(&mut self) -> Vec<protocol::Message> {
+        self.prune();
+        self.messages
+            .iter()
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    fn prune(&mut self) {
+        let config = config::get();
+        while self.messages.len() > config.history_count {
+            self.messages.pop_front();
+        }
+        if let Some(max_age) = config.history_age {
+            while let Some((time, _)) = self.messages.front() {
+                if time.elapsed() <= max_age {
+                    break;
+                }
+                self.messages.pop_front();
+            }
+        }
+    }
+}
+
+/// History frame of a chat message, control fields `code;1, content type;1, payload`. Time, clock
+/// and uid are the ones of the message.
+pub fn wrap(message: &protocol::Message) -> protocol::Message {
+    let mut wrapped = message.clone();
+    wrapped.code = Code::History as u8;
+    wrapped.payload = protocol::Payload::Control(vec![
+        vec![message.code],
+        vec![message.payload.content_type()],
+        message.payload.to_bytes(),
+    ]);
+    wrapped
+}
+
+/// Chat message replayed in a history frame made by `wrap`.
+pub fn unwrap(wrapped: &protocol::Message) -> Option<protocol::Message> {
+    match wrapped.fields() {
+        [code, content_type, payload] if code.len() == 1 && content_type.len() == 1 => {
+            let code = Code::try_from(code[0]).ok()?;
+            let mut message = wrapped.clone();
+            message.code = code as u8;
+            message.payload = protocol::Payload::parse(content_type[0], payload).ok()?;
+            Some(message)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::Style;
+
+    fn from(id: &str, mut message: protocol::Message) -> protocol::Message {
+        message.pro_id.id = id.to_string();
+        message
+    }
+
+    fn texts(history: &mut History) -> Vec<String> {
+        history
+            .recent()
+            .iter()
+            .map(|message| match message.split_about() {
+                Some((_, text)) => text,
+                None => server::chat_text(message).0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn messages_are_kept_in_order() {
+        let mut history = History::new();
+        history.push(&from("alice", server::chat_message("hi", Style::Plain)));
+        history.push(&from("bob", server::chat_message("waves", Style::Action)));
+        assert_eq!(texts(&mut history), vec!["hi", "waves"]);
+        assert_eq!(history.recent()[1].pro_id.id, "bob");
+    }
+
+    #[test]
+    fn edits_fold_into_the_message_of_their_author() {
+        let mut history = History::new();
+        let post = from("alice", server::chat_message("helo", Style::Action));
+        let answer = from(
+            "bob",
+            protocol::Message::new_about(Code::Answer as u8, post.uid, "hi"),
+        );
+        history.push(&post);
+        history.push(&answer);
+
+        history.push(&from(
+            "alice",
+            protocol::Message::new_about(Code::Edit as u8, post.uid, "hello"),
+        ));
+        history.push(&from(
+            "bob",
+            protocol::Message::new_about(Code::Edit as u8, answer.uid, "hey"),
+        ));
+        assert_eq!(texts(&mut history), vec!["hello", "hey"]);
+        // The style and the message replied to are kept
+        let recent = history.recent();
+        assert_eq!(server::chat_text(&recent[0]).1, Style::Action);
+        assert_eq!(recent[1].split_about().unwrap().0, post.uid);
+
+        // An edit of someone else's message is kept as it came, not folded
+        history.push(&from(
+            "carol",
+            protocol::Message::new_about(Code::Edit as u8, post.uid, "mine"),
+        ));
+        assert_eq!(texts(&mut history), vec!["hello", "hey", "mine"]);
+    }
+
+    #[test]
+    fn deletes_take_replies_and_reactions_along() {
+        let mut history = History::new();
+        let post = from("alice", server::chat_message("hi", Style::Plain));
+        let other = from("bob", server::chat_message("yo", Style::Plain));
+        history.push(&post);
+        history.push(&other);
+        history.push(&from(
+            "bob",
+            protocol::Message::new_about(Code::Answer as u8, post.uid, "hey"),
+        ));
+        history.push(&from(
+            "carol",
+            protocol::Message::new_about(Code::React as u8, post.uid, "👍"),
+        ));
+        history.push(&from(
+            "carol",
+            protocol::Message::new_about(Code::React as u8, other.uid, "🎉"),
+        ));
+
+        // Only the author deletes, a delete of someone else is kept as it came
+        history.push(&from(
+            "bob",
+            protocol::Message::new_about(Code::Delete as u8, post.uid, ""),
+        ));
+        assert_eq!(history.recent().len(), 6);
+
+        history.push(&from(
+            "alice",
+            protocol::Message::new_about(Code::Delete as u8, post.uid, ""),
+        ));
+        assert_eq!(texts(&mut history), vec!["yo", "🎉"]);
+    }
+
+    #[test]
+    fn oldest_messages_leave_past_history_count() {
+        let count = config::get().history_count;
+        let mut history = History::new();
+        for index in 0..count + 5 {
+            history.push(&from(
+                "alice",
+                server::chat_message(&index.to_string(), Style::Plain),
+            ));
+        }
+        let texts = texts(&mut history);
+        assert_eq!(texts.len(), count);
+        assert_eq!(texts[0], "5");
+        assert_eq!(texts[count - 1], (count + 4).to_string());
+    }
+}
@@ -1,8 +1,18 @@
 #[macro_use]
 extern crate lazy_static;
 
-pub mod prelude;
 pub mod buf;
+pub mod chatlog;
+pub mod client;
+pub mod command;
+pub mod config;
+pub mod event;
+pub mod history;
+pub mod memo;
+pub mod notify;
+pub mod posts;
+pub mod prelude;
 pub mod protocol;
+pub mod roster;
 pub mod server;
-pub mod client;
\ No newline at end of file
+pub mod transfer;
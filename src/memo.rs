@@ -0,0 +1,187 @@
+use std::{collections::VecDeque, time::Instant};
+
+use crate::prelude::*;
+
+/// What happened to a memo, told back to its author.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum MemoStatus {
+    Delivered = 0,
+    /// Target is offline, the memo waits for it to join again.
+    Queued,
+    /// Nobody with this id has been in the room.
+    Unknown,
+    /// Room owner has no room left for the memo.
+    Full,
+}
+
+impl MemoStatus {
+    pub fn parse(status: u8) -> Option<MemoStatus> {
+        match status {
+            0 => Some(MemoStatus::Delivered),
+            1 => Some(MemoStatus::Queued),
+            2 => Some(MemoStatus::Unknown),
+            3 => Some(MemoStatus::Full),
+            _ => None,
+        }
+    }
+}
+
+/// Memos kept by room owner for members that are offline, and the receipts of memos delivered while
+/// their author was.
+///
+/// Only members that have been in the room can get memos. Storage is bounded by `memo.count`
+/// and `memo.per_member`, and memos older than `memo.age` are dropped.
+#[derive(Default)]
+pub struct MemoStore {
+    /// Raw ids of every member that has joined the room
+    known: Vec<String>,
+    /// Frames with the raw id of the member they wait for
+    memos: VecDeque<(Instant, String, protocol::Message)>,
+}
+
+impl MemoStore {
+    pub fn new() -> MemoStore {
+        MemoStore::default()
+    }
+
+    pub fn know(&mut self, id: &str) {
+        if !self.is_known(id) {
+            self.known.push(id.to_string());
+        }
+    }
+
+    pub fn is_known(&self, id: &str) -> bool {
+        self.known.iter().any(|known| known == id)
+    }
+
+    /// Keep a memo made by `protocol::Message::new_to` until its target joins.
+    pub fn queue(&mut self, memo: &protocol::Message) -> MemoStatus {
+        self.keep(&memo.split_target().0, memo)
+    }
+
+    /// Keep the receipt of a memo until its author joins. It is lost if the store is full.
+    pub fn queue_receipt(&mut self, author: &str, receipt: &protocol::Message) {
+        self.keep(author, receipt);
+    }
+
+    /// Remove and return the memos and receipts waiting for the raw id, oldest first.
+    pub fn take(&mut self, id: &str) -> Vec<protocol::Message> {
+        self.prune();
+        let mut taken = Vec::new();
+        self.memos.retain(|(_, to, memo)| {
+            if to == id {
+                taken.push(memo.clone());
+                false
+            } else {
+                true
+            }
+        });
+        taken
+    }
+
+    fn keep(&mut self, to: &str, message: &protocol::Message) -> MemoStatus {
+        self.prune();
+        let config = config::get();
+        let for_member = self
+            .memos
+            .iter()
+            .filter(|(_, queued, _)| queued == to)
+            .count();
+        if self.memos.len() >= config.memo_count || for_member >= config.memo_per_member {
+            return MemoStatus::Full;
+        }
+        self.memos
+            .push_back((Instant::now(), to.to_string(), message.clone()));
+        MemoStatus::Queued
+    }
+
+    fn prune(&mut self) {
+        if let Some(max_age) = config::get().memo_age {
+            self.memos.retain(|(time, _, _)| time.elapsed() <= max_age);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use server::Code;
+
+    fn memo(target: &str, text: &str) -> protocol::Message {
+        protocol::Message::new_to(Code::Memo as u8, target, text)
+    }
+
+    #[test]
+    fn memos_wait_for_their_target() {
+        let mut store = MemoStore::new();
+        store.know("bob");
+        store.know("bob");
+        assert!(store.is_known("bob"));
+        assert!(!store.is_known("carol"));
+
+        assert_eq!(store.queue(&memo("bob", "first")), MemoStatus::Queued);
+        assert_eq!(store.queue(&memo("carol", "hi")), MemoStatus::Queued);
+        assert_eq!(store.queue(&memo("bob", "second")), MemoStatus::Queued);
+
+        let taken: Vec<_> = store
+            .take("bob")
+            .iter()
+            .map(|memo| memo.split_target().1)
+            .collect();
+        assert_eq!(taken, vec!["first", "second"]);
+        assert!(store.take("bob").is_empty());
+        assert_eq!(store.take("carol").len(), 1);
+    }
+
+    #[test]
+    fn receipts_wait_for_the_author() {
+        let mut store = MemoStore::new();
+        let first = memo("bob", "first");
+        let receipt = protocol::Message::new_to(Code::Receipt as u8, "bob", "");
+        store.queue(&first);
+        store.queue_receipt("alice", &receipt);
+
+        let taken = store.take("alice");
+        assert_eq!(taken.len(), 1);
+        assert_eq!(taken[0].uid, receipt.uid);
+        assert_eq!(store.take("bob")[0].uid, first.uid);
+    }
+
+    #[test]
+    fn store_is_full_per_member_and_in_all() {
+        let config = config::get();
+        let mut store = MemoStore::new();
+        for index in 0..config.memo_per_member {
+            assert_eq!(
+                store.queue(&memo("bob", &index.to_string())),
+                MemoStatus::Queued
+            );
+        }
+        assert_eq!(store.queue(&memo("bob", "one more")), MemoStatus::Full);
+
+        // Others still have room until the store holds `memo.count`
+        let mut target = 0;
+        while store.memos.len() < config.memo_count {
+            let id = format!("member{}", target / config.memo_per_member);
+            assert_eq!(store.queue(&memo(&id, "hi")), MemoStatus::Queued);
+            target += 1;
+        }
+        assert_eq!(store.queue(&memo("carol", "hi")), MemoStatus::Full);
+        assert_eq!(store.take("bob").len(), config.memo_per_member);
+        assert_eq!(store.queue(&memo("carol", "hi")), MemoStatus::Queued);
+    }
+
+    #[test]
+    fn statuses_are_read_back() {
+        for status in [
+            MemoStatus::Delivered,
+            MemoStatus::Queued,
+            MemoStatus::Unknown,
+            MemoStatus::Full,
+        ] {
+            assert_eq!(MemoStatus::parse(status as u8), Some(status));
+        }
+        assert_eq!(MemoStatus::parse(4), None);
+    }
+}
@@ -0,0 +1,124 @@
+use std::{
+    io,
+    ops::Range,
+    process::{Command, Stdio},
+    sync::Mutex,
+    thread,
+};
+
+use crate::prelude::*;
+use config::NotifyLevel;
+
+lazy_static! {
+    /// Level of the room we are in, from the config until changed by `/notify`
+    static ref LEVEL: Mutex<NotifyLevel> = Mutex::new(NotifyLevel::Mentions);
+}
+
+/// Take the level of `room`, from `notify.room.<room>` or else `notify.level`.
+pub fn open_room(room: &str) {
+    let config = config::get();
+    *LEVEL.lock().unwrap() = config
+        .notify_rooms
+        .iter()
+        .rev()
+        .find(|(name, _)| name == room)
+        .map_or(config.notify_level, |(_, level)| *level);
+}
+
+pub fn level() -> NotifyLevel {
+    *LEVEL.lock().unwrap()
+}
+
+/// Change the level of the room we are in, until we leave it.
+pub fn set_level(level: NotifyLevel) {
+    *LEVEL.lock().unwrap() = level;
+}
+
+/// Byte ranges of `@id` in `text`. `@idle` or `me@id` are not mentions of `id`.
+pub fn mentions(text: &str, id: &str) -> Vec<Range<usize>> {
+    if id.is_empty() {
+        return Vec::new();
+    }
+    let mention = format!("@{}", id);
+    text.match_indices(&mention)
+        .map(|(start, _)| start..start + mention.len())
+        .filter(|range| {
+            !text[..range.start].chars().next_back().is_some_and(is_word)
+                && !text[range.end..].chars().next().is_some_and(is_word)
+        })
+        .collect()
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Ring the bell and run `notify.command` for a message of `from`, if the level of the room asks
+/// for it. `direct` is true for mentions, private messages and memos.
+///
+/// Returns the error of the command if it could not be started.
+pub fn message(from: &str, text: &str, direct: bool) -> io::Result<()> {
+    match level() {
+        NotifyLevel::None => return Ok(()),
+        NotifyLevel::Mentions if !direct => return Ok(()),
+        _ => {}
+    }
+    let config = config::get();
+    if config.notify_bell {
+        buf::bell();
+    }
+    let command = match config.notify_command {
+        Some(command) => command,
+        None => return Ok(()),
+    };
+    let mut words = command.split_whitespace();
+    let program = match words.next() {
+        Some(program) => program,
+        None => return Ok(()),
+    };
+    let mut child = Command::new(program)
+        .args(words)
+        .arg(format!("{}: {}", from, text))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    // Reaped on its own thread, a slow command doesn't hold the chat
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_inside_other_words_are_not_mentions() {
+        assert_eq!(mentions("@bob hi", "bob"), vec![0..4]);
+        assert_eq!(mentions("hi @bob and @bob", "bob"), vec![3..7, 12..16]);
+        assert!(mentions("@bobby hi", "bob").is_empty());
+        assert!(mentions("@bob_2 @bob-2 hi", "bob").is_empty());
+        assert!(mentions("mail me@bob", "bob").is_empty());
+        assert!(mentions("bob", "bob").is_empty());
+        assert!(mentions("@bob", "").is_empty());
+        // A longer id is still mentioned whole
+        assert_eq!(mentions("@bobby hi", "bobby"), vec![0..6]);
+    }
+
+    #[test]
+    fn punctuation_ends_a_mention() {
+        assert_eq!(mentions("@bob, hi", "bob"), vec![0..4]);
+        assert_eq!(mentions("hi @bob!", "bob"), vec![3..7]);
+        assert_eq!(mentions("(@bob)", "bob"), vec![1..5]);
+        assert_eq!(mentions("@bob's turn", "bob"), vec![0..4]);
+        assert_eq!(mentions("@bob.", "bob"), vec![0..4]);
+    }
+
+    #[test]
+    fn cjk_ids_are_mentioned() {
+        assert_eq!(mentions("@张三 你好", "张三"), vec![0..7]);
+        assert_eq!(mentions("你好，@张三。", "张三"), vec![9..16]);
+        assert!(mentions("@张三丰 你好", "张三").is_empty());
+        assert!(mentions("你好@张三", "张三").is_empty());
+    }
+}
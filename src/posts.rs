@@ -0,0 +1,385 @@
+use std::{convert::TryFrom, sync::Mutex};
+
+use crate::prelude::*;
+use server::Code;
+
+/// How many recent messages can still be replied to, edited or reacted to.
+const POSTS_MAX: usize = 200;
+/// Time shown before every message
+const TIME_FORMAT: &str = "%H:%M";
+/// Columns of the message quoted by a reply
+const QUOTE_WIDTH: usize = 24;
+
+/// A chat message as shown in message area, with what happened to it since.
+#[derive(Clone, Debug)]
+pub struct Post {
+    pub uid: u64,
+    /// Id of the author
+    pub author: String,
+    pub text: String,
+    /// Sent by `/me`
+    pub action: bool,
+    pub time: u64,
+    pub clock: u64,
+    pub reply_to: Option<u64>,
+    /// Emoji and the ids of the members who reacted with it
+    pub reactions: Vec<(String, Vec<String>)>,
+    pub edited: bool,
+    pub deleted: bool,
+    /// Replayed by room owner
+    pub history: bool,
+}
+
+lazy_static! {
+    static ref POSTS: Mutex<Vec<Post>> = Mutex::new(Vec::new());
+}
+
+pub fn clear() {
+    POSTS.lock().unwrap().clear();
+}
+
+/// Take a chat message into account. Returns the uid of the message to show again, `None` if
+/// nothing changed: unknown target, edit or delete by someone else than the author, or a message
+/// already seen.
+pub fn apply(message: &protocol::Message, history: bool) -> Option<u64> {
+    let mut lock_posts = POSTS.lock().unwrap();
+    let code = Code::try_from(message.code).unwrap_or(Code::None);
+    let (reply_to, text, style) = match code {
+        Code::Message => {
+            let (text, style) = server::chat_text(message);
+            (None, text, style)
+        }
+        _ => {
+            let (target, text) = message.split_about()?;
+            (Some(target), text, server::Style::Plain)
+        }
+    };
+
+    if let Code::Message | Code::Answer = code {
+        if lock_posts.iter().any(|post| post.uid == message.uid) {
+            return None;
+        }
+        lock_posts.push(Post {
+            uid: message.uid,
+            author: message.pro_id.id.clone(),
+            text,
+            action: style == server::Style::Action,
+            time: message.time,
+            clock: message.clock,
+            reply_to,
+            reactions: Vec::new(),
+            edited: false,
+            deleted: false,
+            history,
+        });
+        if lock_posts.len() > POSTS_MAX {
+            lock_posts.remove(0);
+        }
+        return Some(message.uid);
+    }
+
+    let target = reply_to?;
+    let post = lock_posts
+        .iter_mut()
+        .find(|post| post.uid == target && !post.deleted)?;
+    match code {
+        Code::Edit if post.author == message.pro_id.id => {
+            post.text = text;
+            post.edited = true;
+        }
+        Code::Delete if post.author == message.pro_id.id => {
+            post.text.clear();
+            post.reactions.clear();
+            post.deleted = true;
+        }
+        Code::React if !text.is_empty() => {
+            match post.reactions.iter_mut().find(|(emoji, _)| *emoji == text) {
+                Some((_, members)) if members.contains(&message.pro_id.id) => return None,
+                Some((_, members)) => members.push(message.pro_id.id.clone()),
+                None => post.reactions.push((text, vec![message.pro_id.id.clone()])),
+            }
+        }
+        _ => return None,
+    }
+    Some(target)
+}
+
+pub fn get(uid: u64) -> Option<Post> {
+    POSTS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|post| post.uid == uid)
+        .cloned()
+}
+
+/// Most recent message whose uid ends with the hex digits of `reference`, `#` in front is allowed.
+pub fn find(reference: &str) -> Option<u64> {
+    let reference = reference.trim_start_matches('#').to_lowercase();
+    if reference.is_empty() || !reference.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    POSTS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|post| !post.deleted && format!("{:016x}", post.uid).ends_with(&reference))
+        .map(|post| post.uid)
+}
+
+/// Messages replying to `uid`, they quote it.
+pub fn replies_to(uid: u64) -> Vec<u64> {
+    POSTS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|post| post.reply_to == Some(uid))
+        .map(|post| post.uid)
+        .collect()
+}
+
+/// Most recent message of the member that is not deleted.
+pub fn last_of(author: &str) -> Option<u64> {
+    POSTS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|post| post.author == author && !post.deleted)
+        .map(|post| post.uid)
+}
+
+/// The member `old` is now called `new`, it can still edit and delete its messages.
+pub fn rename(old: &str, new: &str) {
+    for post in POSTS.lock().unwrap().iter_mut() {
+        if post.author == old {
+            post.author = new.to_string();
+        }
+        for (_, members) in post.reactions.iter_mut() {
+            for member in members.iter_mut().filter(|member| *member == old) {
+                *member = new.to_string();
+            }
+        }
+    }
+}
+
+/// Line of the message in message area. A message replayed by room owner is shown all in the
+/// history tone, others have the id in its color. A `/me` message reads as `* alice waves`.
+pub fn render(post: &Post) -> String {
+    let paint = |text: &str, tone: buf::Tone| {
+        if post.history {
+            text.to_string()
+        } else {
+            buf::paint(text, tone)
+        }
+    };
+    let author = if post.history {
+        post.author.clone()
+    } else {
+        buf::paint_id(&post.author)
+    };
+    let action = post.action && !post.deleted;
+    let head = format!(
+        "{} #{} {}",
+        chatlog::local_time(post.time).format(TIME_FORMAT),
+        protocol::short_uid(post.uid),
+        if action {
+            format!("* {} ", author)
+        } else {
+            format!("{}:\t", author)
+        }
+    );
+
+    let mut line = String::new();
+    if post.deleted {
+        line.push_str(&paint("(message deleted)", buf::Tone::Muted));
+    } else {
+        if let Some(target) = post.reply_to {
+            let quote = match get(target) {
+                Some(quoted) if quoted.deleted => String::from("> (message deleted)"),
+                Some(quoted) => format!(
+                    "> {}: {}",
+                    quoted.author,
+                    buf::truncate(&quoted.text.replace('\n', " "), QUOTE_WIDTH)
+                ),
+                None => format!("> #{}", protocol::short_uid(target)),
+            };
+            line.push_str(&format!("{} ", paint(&quote, buf::Tone::Quote)));
+        }
+        line.push_str(&highlight(&post.text, post));
+        if post.edited {
+            line.push_str(&format!(" {}", paint("(edited)", buf::Tone::Muted)));
+        }
+        for (emoji, members) in post.reactions.iter() {
+            line.push_str(&format!(" [{} {}]", emoji, members.len()));
+        }
+    }
+
+    if post.history {
+        buf::paint(&format!("{}{}", head, line), buf::Tone::History)
+    } else {
+        format!("{}{}", head, line)
+    }
+}
+
+/// The text with our id painted where the author mentions us.
+fn highlight(text: &str, post: &Post) -> String {
+    let me = protocol::get_id().unwrap_or_default();
+    if post.history || post.author == me {
+        return text.to_string();
+    }
+    let mut line = String::new();
+    let mut last = 0;
+    for range in notify::mentions(text, &me) {
+        line.push_str(&text[last..range.start]);
+        line.push_str(&buf::paint(&text[range.clone()], buf::Tone::Mention));
+        last = range.end;
+    }
+    line.push_str(&text[last..]);
+    line
+}
+
+/// Text of a chat message for the chat log.
+pub fn describe(message: &protocol::Message) -> String {
+    let code = Code::try_from(message.code).unwrap_or(Code::None);
+    if code == Code::Message {
+        return match server::chat_text(message) {
+            (text, server::Style::Action) => format!("* {}", text),
+            (text, _) => text,
+        };
+    }
+    let (target, text) = match message.split_about() {
+        Some((target, text)) => (protocol::short_uid(target), text),
+        None => return message.text(),
+    };
+    match code {
+        Code::Answer => format!("(reply to #{}) {}", target, text),
+        Code::Edit => format!("(edit of #{}) {}", target, text),
+        Code::Delete => format!("(deleted #{})", target),
+        Code::React => format!("(reacted to #{}) {}", target, text),
+        _ => message.text(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from(author: &str, mut message: protocol::Message) -> protocol::Message {
+        message.pro_id.id = author.to_string();
+        message
+    }
+
+    fn about(author: &str, code: Code, target: u64, text: &str) -> protocol::Message {
+        from(
+            author,
+            protocol::Message::new_about(code as u8, target, text),
+        )
+    }
+
+    #[test]
+    fn messages_are_kept_once() {
+        let hello = from("bob", protocol::Message::new(Code::Message as u8, "hello"));
+        assert_eq!(apply(&hello, false), Some(hello.uid));
+        assert_eq!(apply(&hello, true), None);
+        let post = get(hello.uid).unwrap();
+        assert_eq!((post.author.as_str(), post.text.as_str()), ("bob", "hello"));
+        assert!(!post.history && !post.action);
+
+        let wave = from("bob", server::chat_message("waves", server::Style::Action));
+        apply(&wave, false);
+        assert!(get(wave.uid).unwrap().action);
+
+        let answer = about("carol", Code::Answer, hello.uid, "hi bob");
+        assert_eq!(apply(&answer, false), Some(answer.uid));
+        assert_eq!(get(answer.uid).unwrap().reply_to, Some(hello.uid));
+        assert_eq!(replies_to(hello.uid), vec![answer.uid]);
+    }
+
+    #[test]
+    fn only_the_author_edits_and_deletes() {
+        let hello = from("bob", protocol::Message::new(Code::Message as u8, "hello"));
+        apply(&hello, false);
+
+        assert_eq!(
+            apply(&about("carol", Code::Edit, hello.uid, "hacked"), false),
+            None
+        );
+        assert_eq!(
+            apply(&about("carol", Code::Delete, hello.uid, ""), false),
+            None
+        );
+        assert_eq!(get(hello.uid).unwrap().text, "hello");
+
+        assert_eq!(
+            apply(&about("bob", Code::Edit, hello.uid, "hello all"), false),
+            Some(hello.uid)
+        );
+        let post = get(hello.uid).unwrap();
+        assert_eq!(post.text, "hello all");
+        assert!(post.edited);
+
+        apply(&about("carol", Code::React, hello.uid, "👍"), false);
+        assert_eq!(
+            apply(&about("bob", Code::Delete, hello.uid, ""), false),
+            Some(hello.uid)
+        );
+        let post = get(hello.uid).unwrap();
+        assert!(post.deleted && post.text.is_empty() && post.reactions.is_empty());
+
+        // Nothing changes a deleted message
+        assert_eq!(
+            apply(&about("bob", Code::Edit, hello.uid, "back"), false),
+            None
+        );
+        assert_eq!(
+            apply(&about("carol", Code::React, hello.uid, "👍"), false),
+            None
+        );
+    }
+
+    #[test]
+    fn reactions_count_members_once() {
+        let hello = from("bob", protocol::Message::new(Code::Message as u8, "hello"));
+        apply(&hello, false);
+
+        assert_eq!(
+            apply(&about("carol", Code::React, hello.uid, "👍"), false),
+            Some(hello.uid)
+        );
+        assert_eq!(
+            apply(&about("carol", Code::React, hello.uid, "👍"), false),
+            None
+        );
+        apply(&about("dave", Code::React, hello.uid, "👍"), false);
+        apply(&about("dave", Code::React, hello.uid, "🎉"), false);
+        assert_eq!(
+            apply(&about("dave", Code::React, hello.uid, ""), false),
+            None
+        );
+
+        let reactions = get(hello.uid).unwrap().reactions;
+        assert_eq!(
+            reactions,
+            vec![
+                (
+                    String::from("👍"),
+                    vec![String::from("carol"), String::from("dave")]
+                ),
+                (String::from("🎉"), vec![String::from("dave")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_targets_change_nothing() {
+        let target = protocol::Message::new(Code::None as u8, "").uid;
+        assert_eq!(
+            apply(&about("bob", Code::Edit, target, "text"), false),
+            None
+        );
+        assert_eq!(apply(&about("bob", Code::React, target, "👍"), false), None);
+        assert_eq!(get(target).map(|post| post.uid), None);
+    }
+}
@@ -1,5 +1,15 @@
 pub use super::buf;
+pub use super::chatlog;
+pub use super::client;
+pub use super::command;
+pub use super::config;
+pub use super::event;
+pub use super::history;
+pub use super::memo;
+pub use super::notify;
+pub use super::posts;
 pub use super::protocol;
+pub use super::roster;
 pub use super::server;
-pub use super::client;
-pub use std::io;
\ No newline at end of file
+pub use super::transfer;
+pub use std::io;
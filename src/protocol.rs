@@ -1,4 +1,17 @@
-use std::{io, usize};
+use std::{
+    collections::hash_map::RandomState,
+    convert::TryInto,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::server::{self, Code, ReplyResult};
 
 #[derive(Clone, Debug)]
 pub struct ProtocolID {
@@ -6,40 +19,240 @@ pub struct ProtocolID {
     pub id: String,
 }
 
-static mut PROTOCOL: ProtocolID = ProtocolID {
-    protocol: String::new(),
-    id: String::new(),
-};
+lazy_static! {
+    static ref PROTOCOL: Mutex<ProtocolID> = Mutex::new(ProtocolID {
+        protocol: String::new(),
+        id: String::new(),
+    });
+}
 
-pub const ID_LEN: usize = 12;
+/// Most bytes of an id, longer ones are cut on a char boundary.
+pub const ID_MAX_LEN: usize = 32;
+/// Bytes of the id of a v1 frame, padded with NUL.
+pub const ID_V1_LEN: usize = 12;
+/// First byte of the id, followed by `[len;1][id;len]`. Never found in UTF-8, so it can't start
+/// a v1 id.
+pub const ID_MARK: u8 = 0xff;
+pub const ID_LEN_LEN: usize = 1;
 pub const PROTOCOL_LEN: usize = 4;
 pub const CODE_LEN: usize = 1;
+pub const CONTENT_LEN: usize = 1;
+pub const TIME_LEN: usize = 8;
+pub const CLOCK_LEN: usize = 8;
+pub const UID_LEN: usize = 8;
+/// Bytes before the message body, besides the id
+pub const HEADER_LEN: usize =
+    PROTOCOL_LEN + CODE_LEN + CONTENT_LEN + TIME_LEN + CLOCK_LEN + UID_LEN;
+/// Bytes before the message body with the longest id
+pub const HEADER_MAX_LEN: usize = HEADER_LEN + 1 + ID_LEN_LEN + ID_MAX_LEN;
+/// Most bytes of a frame, the size of the receive buffer. Longer frames are cut and can't be read.
+pub const FRAME_MAX_LEN: usize = 512;
+/// Content types of the payload
+pub const CONTENT_TEXT: u8 = 0;
+pub const CONTENT_BINARY: u8 = 1;
+pub const CONTENT_CONTROL: u8 = 2;
+/// Bytes before each field of a control payload, holding its length
+pub const FIELD_LEN_LEN: usize = 2;
+
+/// Lamport clock of this client, see `tick` and `observe`.
+static CLOCK: AtomicU64 = AtomicU64::new(0);
+/// Messages made by this client, mixed into their uid.
+static UID_COUNT: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    /// Randomly seeded, so two clients don't make the same uids.
+    static ref UID_STATE: RandomState = RandomState::new();
+}
+
+/// Body of a message. Its content type is sent in the frame, right after the code.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Payload {
+    /// UTF-8 text
+    Text(String),
+    Binary(Vec<u8>),
+    /// Fields of a control message, each sent as `[len;2][bytes;len]`
+    Control(Vec<Vec<u8>>),
+}
+
+impl Payload {
+    pub fn content_type(&self) -> u8 {
+        match self {
+            Payload::Text(_) => CONTENT_TEXT,
+            Payload::Binary(_) => CONTENT_BINARY,
+            Payload::Control(_) => CONTENT_CONTROL,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Payload::Text(text) => text.as_bytes().to_vec(),
+            Payload::Binary(data) => data.clone(),
+            Payload::Control(fields) => {
+                let mut bytes = Vec::new();
+                for field in fields.iter() {
+                    bytes.extend(&(field.len() as u16).to_be_bytes());
+                    bytes.extend(field);
+                }
+                bytes
+            }
+        }
+    }
+
+    pub fn parse(content_type: u8, bytes: &[u8]) -> Result<Payload, io::Error> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        match content_type {
+            CONTENT_TEXT => String::from_utf8(bytes.to_vec())
+                .map(Payload::Text)
+                .map_err(|_| invalid("Text is not UTF-8")),
+            CONTENT_BINARY => Ok(Payload::Binary(bytes.to_vec())),
+            CONTENT_CONTROL => {
+                let mut fields = Vec::new();
+                let mut rest = bytes;
+                while !rest.is_empty() {
+                    if rest.len() < FIELD_LEN_LEN {
+                        return Err(invalid("Control field too short"));
+                    }
+                    let len = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+                    let field = rest
+                        .get(FIELD_LEN_LEN..FIELD_LEN_LEN + len)
+                        .ok_or_else(|| invalid("Control field too short"))?;
+                    fields.push(field.to_vec());
+                    rest = &rest[FIELD_LEN_LEN + len..];
+                }
+                Ok(Payload::Control(fields))
+            }
+            _ => Err(invalid("Unknown content type")),
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Message {
     pub code: u8,
-    pub message: String,
+    pub payload: Payload,
     pub pro_id: ProtocolID,
+    /// Wall time of the sender, in milliseconds since the Unix epoch
+    pub time: u64,
+    /// Lamport clock of the sender. Ordering by `clock`, then `time`, gives the same order on every member.
+    pub clock: u64,
+    /// Unique id of the message, used to reply to it, edit it, delete it or react to it
+    pub uid: u64,
 }
 
 impl Message {
-    pub fn new(code: u8, messa: &String) -> Message {
+    pub fn new(code: u8, messa: &str) -> Message {
+        Message::with_payload(code, Payload::Text(messa.to_string()))
+    }
+
+    pub fn with_payload(code: u8, payload: Payload) -> Message {
         Message {
             code,
-            message: messa.clone(),
-            pro_id: unsafe { PROTOCOL.clone() },
+            payload,
+            pro_id: PROTOCOL.lock().unwrap().clone(),
+            time: now_millis(),
+            clock: tick(),
+            uid: new_uid(),
+        }
+    }
+
+    /// Message about an earlier message, control fields `target uid;8[, text]`. A delete has no
+    /// text.
+    pub fn new_about(code: u8, target: u64, messa: &str) -> Message {
+        let mut fields = vec![target.to_be_bytes().to_vec()];
+        if !messa.is_empty() {
+            fields.push(messa.as_bytes().to_vec());
+        }
+        Message::with_payload(code, Payload::Control(fields))
+    }
+
+    /// Target uid and text of a message made by `new_about`.
+    pub fn split_about(&self) -> Option<(u64, String)> {
+        let target = self.fields().first().filter(|uid| uid.len() == UID_LEN)?;
+        Some((field_u64(target)?, self.field_text(1)))
+    }
+
+    /// Message for one member, control fields `target id, text`.
+    pub fn new_to(code: u8, target: &str, messa: &str) -> Message {
+        Message::with_payload(
+            code,
+            Payload::Control(vec![
+                clean_id(target).into_bytes(),
+                messa.as_bytes().to_vec(),
+            ]),
+        )
+    }
+
+    /// Target id and text of a message made by `new_to`.
+    pub fn split_target(&self) -> (String, String) {
+        (self.field_text(0), self.field_text(1))
+    }
+
+    /// Text of the payload, binary data is read as UTF-8 and a control payload has none. Cleaned by
+    /// `clean_text`, like the fields.
+    pub fn text(&self) -> String {
+        match &self.payload {
+            Payload::Text(text) => clean_text(text),
+            Payload::Binary(data) => clean_text(&String::from_utf8_lossy(data)),
+            Payload::Control(_) => String::new(),
         }
     }
 
+    /// Fields of a control payload, none for other payloads.
+    pub fn fields(&self) -> &[Vec<u8>] {
+        match &self.payload {
+            Payload::Control(fields) => fields,
+            _ => &[],
+        }
+    }
+
+    /// Control field read as UTF-8 and cleaned by `clean_text`, empty if there is none at `index`.
+    pub fn field_text(&self, index: usize) -> String {
+        self.fields()
+            .get(index)
+            .map(|field| clean_text(&String::from_utf8_lossy(field)))
+            .unwrap_or_default()
+    }
+
+    /// Read a frame, its id cleaned like the ones we send. Frames without the id mark are read as
+    /// v1, see `parse_v1`.
     pub fn parse(mes: &[u8]) -> Result<Message, io::Error> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if mes.len() > PROTOCOL_LEN && mes[PROTOCOL_LEN] != ID_MARK {
+            return Message::parse_v1(mes);
+        }
+        if mes.len() < HEADER_LEN + ID_LEN_LEN {
+            return Err(invalid("Message too short"));
+        }
+        let len_start = PROTOCOL_LEN + 1;
+        let id_start = len_start + ID_LEN_LEN;
+        let id_end = id_start + mes[len_start] as usize;
+        if id_end - id_start > ID_MAX_LEN {
+            return Err(invalid("Id too long"));
+        }
+        let id = mes
+            .get(id_start..id_end)
+            .ok_or_else(|| invalid("Message too short"))?;
+        let id = String::from_utf8(id.to_vec()).map_err(|_| invalid("Id is not UTF-8"))?;
+        // Ids end up in the roster and on screen, whatever the sender put in them
+        let id = clean_id(&id);
+        if mes.len() < HEADER_LEN + id_end - PROTOCOL_LEN {
+            return Err(invalid("Message too short"));
+        }
+        let code_start = id_end;
+        let time_start = code_start + CODE_LEN + CONTENT_LEN;
+        let clock_start = time_start + TIME_LEN;
+        let uid_start = clock_start + CLOCK_LEN;
+        let body_start = uid_start + UID_LEN;
         let message = Message {
-            code: mes[(ID_LEN + PROTOCOL_LEN)],
-            message: String::from_utf8_lossy(&mes[(ID_LEN + PROTOCOL_LEN + CODE_LEN)..]).to_string(),
+            code: mes[code_start],
+            payload: Payload::parse(mes[code_start + CODE_LEN], &mes[body_start..])?,
             pro_id: ProtocolID {
                 protocol: String::from_utf8_lossy(&mes[..PROTOCOL_LEN]).to_string(),
-                id: String::from_utf8_lossy(&mes[PROTOCOL_LEN..(PROTOCOL_LEN + ID_LEN)])
-                    .to_string(),
+                id,
             },
+            time: u64::from_be_bytes(mes[time_start..clock_start].try_into().unwrap()),
+            clock: u64::from_be_bytes(mes[clock_start..uid_start].try_into().unwrap()),
+            uid: u64::from_be_bytes(mes[uid_start..body_start].try_into().unwrap()),
         };
         let de_protocol = get_protocol().unwrap();
         if message.pro_id.protocol != de_protocol {
@@ -52,89 +265,183 @@ impl Message {
         }
     }
 
-    pub fn parse_id(raw_id: &String) -> String {
-        let mut id = String::new();
-        let raw_id: Vec<char> = raw_id.chars().collect();
-        let mut index = (raw_id.len() - 1) as i32;
-        while index >= 0 {
-            if raw_id[index as usize] != '0' || raw_id[index as usize] != '\0' {
-                break;
-            }
-            index -= 1;
+    /// Read a v1 frame, `[protocol;4][id;12][code;1][text]`. Its codes `0` to `3` are taken as the
+    /// request, reply, message and exit of this version, with the fields these expect. It has no
+    /// time, clock or uid, they are made when it is read.
+    fn parse_v1(mes: &[u8]) -> Result<Message, io::Error> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let code_start = PROTOCOL_LEN + ID_V1_LEN;
+        if mes.len() < code_start + CODE_LEN {
+            return Err(invalid("Message too short"));
         }
-
-        if index >= 0 {
-            for i in 0..=index as usize {
-                id.push(raw_id[i]);
+        let id = clean_id(
+            String::from_utf8_lossy(&mes[PROTOCOL_LEN..code_start]).trim_end_matches('\0'),
+        );
+        let text = String::from_utf8_lossy(&mes[code_start + CODE_LEN..]).to_string();
+        let (code, payload) = match mes[code_start] {
+            // v1 clients leave with a request too
+            0 if text == server::EXIT_ROOM => (Code::Exit, Payload::Control(Vec::new())),
+            0 => (Code::Request, Payload::Control(vec![text.into_bytes()])),
+            1 => {
+                let result = if text == server::JOIN_SUCCESS {
+                    ReplyResult::Accepted
+                } else {
+                    ReplyResult::WrongKey
+                };
+                let fields = vec![
+                    vec![result as u8],
+                    text.into_bytes(),
+                    id.clone().into_bytes(),
+                ];
+                (Code::Reply, Payload::Control(fields))
             }
+            2 => (Code::Message, Payload::Text(text)),
+            3 => (Code::Exit, Payload::Control(Vec::new())),
+            _ => return Err(invalid("Unknown code of a v1 frame")),
+        };
+        let message = Message {
+            pro_id: ProtocolID {
+                protocol: String::from_utf8_lossy(&mes[..PROTOCOL_LEN]).to_string(),
+                id,
+            },
+            ..Message::with_payload(code as u8, payload)
+        };
+        if Some(&message.pro_id.protocol) != get_protocol().as_ref() {
+            return Err(invalid("Protocol invalid"));
         }
-        id
+        Ok(message)
     }
 
+    /// Frame of the message.
     pub fn to_buf(&self) -> Vec<u8> {
+        let id = clean_id(&self.pro_id.id);
         let mut res: Vec<u8> = Vec::new();
         Message::push_to_vec(&mut res, self.pro_id.protocol.clone());
-        Message::push_to_vec(&mut res, self.pro_id.id.clone());
+        res.push(ID_MARK);
+        res.push(id.len() as u8);
+        Message::push_to_vec(&mut res, id);
         res.push(self.code);
-        Message::push_to_vec(&mut res, self.message.clone());
+        res.push(self.payload.content_type());
+        res.extend(&self.time.to_be_bytes());
+        res.extend(&self.clock.to_be_bytes());
+        res.extend(&self.uid.to_be_bytes());
+        res.extend(self.payload.to_bytes());
         res
     }
 
     fn push_to_vec(target: &mut Vec<u8>, src: String) {
-        for c in src.bytes() {
-            target.push(c);
-        }
+        target.extend(src.bytes());
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:\t{}", self.pro_id.id, self.text())
     }
 }
 
-impl ToString for Message {
-    fn to_string(&self) -> String {
-        format!("{}:\t{}", Message::parse_id(&self.pro_id.id), self.message)
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn new_uid() -> u64 {
+    let mut hasher = UID_STATE.build_hasher();
+    hasher.write_u64(now_millis());
+    hasher.write_u64(UID_COUNT.fetch_add(1, Ordering::SeqCst));
+    hasher.finish()
+}
+
+/// Number held by a field of a control payload, big-endian on up to 8 bytes.
+pub fn field_u64(field: &[u8]) -> Option<u64> {
+    if field.len() > 8 {
+        return None;
     }
+    Some(
+        field
+            .iter()
+            .fold(0, |number, byte| number << 8 | *byte as u64),
+    )
+}
+
+/// Last hex digits of a uid, enough to tell recent messages apart in commands.
+pub fn short_uid(uid: u64) -> String {
+    format!("{:04x}", uid & 0xffff)
+}
+
+/// Advance the Lamport clock for a message we send.
+pub fn tick() -> u64 {
+    CLOCK.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+/// Move the Lamport clock past a message we receive.
+pub fn observe(clock: u64) {
+    CLOCK.fetch_max(clock, Ordering::SeqCst);
+}
+
+pub fn clock() -> u64 {
+    CLOCK.load(Ordering::SeqCst)
 }
 
 pub fn set_protocol(protocol: String) {
-    unsafe {
-        if protocol.len() >= PROTOCOL_LEN {
-            PROTOCOL.protocol = protocol[..PROTOCOL_LEN].to_string();
-        } else {
-            PROTOCOL.protocol = protocol;
-            for _ in 0..PROTOCOL_LEN - PROTOCOL.protocol.len() {
-                PROTOCOL.protocol.push('\0');
-            }
+    let mut lock_protocol = PROTOCOL.lock().unwrap();
+    if protocol.len() >= PROTOCOL_LEN {
+        lock_protocol.protocol = protocol[..PROTOCOL_LEN].to_string();
+    } else {
+        lock_protocol.protocol = protocol;
+        while lock_protocol.protocol.len() < PROTOCOL_LEN {
+            lock_protocol.protocol.push('\0');
         }
     }
 }
 
-pub fn set_id(id: &String) {
-    unsafe {
-        let mut id = id.clone();
-        if id.len() < ID_LEN as usize {
-            let len = id.len();
-            for _ in 0..(ID_LEN as usize - len) {
-                id.push('\0');
-            }
+/// Id as it is sent: trimmed, without control characters and cut to `ID_MAX_LEN` bytes on a char
+/// boundary.
+pub fn clean_id(id: &str) -> String {
+    let mut clean = String::new();
+    for c in id.trim().chars().filter(|c| !c.is_control()) {
+        if clean.len() + c.len_utf8() > ID_MAX_LEN {
+            break;
         }
-        PROTOCOL.id = String::from(&id[..ID_LEN as usize]);
+        clean.push(c);
     }
+    clean.trim_end().to_string()
+}
+
+/// Text of another member as it can be shown: without the control chars, escape included, that
+/// would drive the terminal. New lines and tabs are kept.
+pub fn clean_text(text: &str) -> String {
+    text.chars()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect()
+}
+
+/// The id can be used in a room: it is sent as it is, and has no white space so commands can name
+/// it.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty() && !id.contains(char::is_whitespace) && clean_id(id) == id
+}
+
+pub fn set_id(id: &str) {
+    PROTOCOL.lock().unwrap().id = clean_id(id);
 }
 
 pub fn get_protocol() -> Option<String> {
-    unsafe {
-        if PROTOCOL.protocol != "" {
-            Some(PROTOCOL.protocol.to_string())
-        } else {
-            None
-        }
+    let lock_protocol = PROTOCOL.lock().unwrap();
+    if !lock_protocol.protocol.is_empty() {
+        Some(lock_protocol.protocol.to_string())
+    } else {
+        None
     }
 }
 
 pub fn get_id() -> Option<String> {
-    unsafe {
-        if PROTOCOL.id != "" {
-            Some(PROTOCOL.id.to_string())
-        } else {
-            None
-        }
+    let lock_protocol = PROTOCOL.lock().unwrap();
+    if !lock_protocol.id.is_empty() {
+        Some(lock_protocol.id.to_string())
+    } else {
+        None
     }
 }
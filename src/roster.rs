@@ -0,0 +1,155 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::server::Presence;
+
+/// A member is idle when it has sent nothing for this long, or told it is.
+pub const IDLE_AFTER: Duration = Duration::from_secs(5 * 60);
+/// How long a member is shown typing after it last told it is.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// A member of the room as shown beside message area.
+#[derive(Clone, Debug)]
+pub struct RosterMember {
+    pub id: String,
+    pub owner: bool,
+    /// When it joined or last sent a message or was typing
+    pub last_active: Instant,
+    /// Active, idle or away, as told by the member. Never typing, see `typing_at`.
+    pub presence: Presence,
+    /// Reason it is away
+    pub status: String,
+    /// When it last told it is typing, cleared by its next message
+    pub typing_at: Option<Instant>,
+}
+
+impl RosterMember {
+    fn new(id: &str, owner: bool) -> RosterMember {
+        RosterMember {
+            id: id.to_string(),
+            owner,
+            last_active: Instant::now(),
+            presence: Presence::Active,
+            status: String::new(),
+            typing_at: None,
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.presence == Presence::Idle || self.last_active.elapsed() > IDLE_AFTER
+    }
+
+    pub fn is_away(&self) -> bool {
+        self.presence == Presence::Away
+    }
+
+    pub fn is_typing(&self) -> bool {
+        self.typing_at
+            .is_some_and(|typing_at| typing_at.elapsed() < TYPING_TIMEOUT)
+    }
+}
+
+lazy_static! {
+    /// Members of the room we are in, room owner first, then by join order
+    static ref ROSTER: Mutex<Vec<RosterMember>> = Mutex::new(Vec::new());
+}
+
+/// Start over with the room of `owner`, members are added by `join`.
+pub fn reset(owner: &str) {
+    let mut lock_roster = ROSTER.lock().unwrap();
+    lock_roster.clear();
+    lock_roster.push(RosterMember::new(owner, true));
+}
+
+pub fn join(id: &str) {
+    let mut lock_roster = ROSTER.lock().unwrap();
+    if !lock_roster.iter().any(|member| member.id == id) {
+        lock_roster.push(RosterMember::new(id, false));
+    }
+}
+
+pub fn leave(id: &str) {
+    ROSTER
+        .lock()
+        .unwrap()
+        .retain(|member| member.owner || member.id != id);
+}
+
+/// The member changed its id, it keeps its place and whether it is room owner.
+pub fn rename(old: &str, new: &str) {
+    if let Some(member) = ROSTER
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .find(|member| member.id == old)
+    {
+        member.id = new.to_string();
+    }
+}
+
+/// The member sent something, it is no longer idle or typing.
+pub fn active(id: &str) {
+    if let Some(member) = ROSTER
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .find(|member| member.id == id)
+    {
+        member.last_active = Instant::now();
+        member.typing_at = None;
+        if member.presence == Presence::Idle {
+            member.presence = Presence::Active;
+        }
+    }
+}
+
+/// Take what the member told it is doing. Returns what it was doing before, `None` if it is not
+/// in the room.
+pub fn set_presence(id: &str, presence: Presence, status: &str) -> Option<Presence> {
+    let mut lock_roster = ROSTER.lock().unwrap();
+    let member = lock_roster.iter_mut().find(|member| member.id == id)?;
+    let was = member.presence;
+    match presence {
+        Presence::Typing => {
+            member.typing_at = Some(Instant::now());
+            member.last_active = Instant::now();
+        }
+        Presence::Active => {
+            member.presence = Presence::Active;
+            member.status.clear();
+            member.last_active = Instant::now();
+        }
+        Presence::Idle | Presence::Away => {
+            member.presence = presence;
+            member.status = status.to_string();
+            member.typing_at = None;
+        }
+    }
+    Some(was)
+}
+
+/// Ids of the members typing now.
+pub fn typing() -> Vec<String> {
+    ROSTER
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|member| member.is_typing())
+        .map(|member| member.id.clone())
+        .collect()
+}
+
+pub fn owner() -> Option<String> {
+    ROSTER
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|member| member.owner)
+        .map(|member| member.id.clone())
+}
+
+pub fn members() -> Vec<RosterMember> {
+    ROSTER.lock().unwrap().clone()
+}
@@ -1,13 +1,19 @@
 use std::{
-    collections::VecDeque,
-    io, mem,
+    convert::TryFrom,
+    io,
     net::{SocketAddr, UdpSocket},
-    sync::{Arc, Mutex},
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
-use crossterm::style::{Colorize, Styler};
-
 use crate::prelude::*;
+use event::{Event, EventBus, JoinResult};
+use history::History;
+use memo::{MemoStatus, MemoStore};
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Identity {
@@ -26,85 +32,436 @@ impl Identity {
     }
 }
 
+/// Code of a frame, the byte after the id. The Readme documents the same numbers.
+///
+/// Codes from `EXTENSION_CODES` are reserved for extensions, the others not listed here for later
+/// versions. A frame with a code we don't know is ignored.
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub enum Code {
-    Request = 0,
-    Reply,
-    Message,
-    Exit,
-    None,
+    None = 0,
+    /// Join request, control fields `key`
+    Request = 1,
+    /// Answer to a join or leave, control fields `result, reason[, owner id, members]`
+    Reply = 2,
+    Message = 3,
+    /// Leave request, no fields
+    Exit = 4,
+    /// Room closed by its owner, control fields `reason`
+    Close = 5,
+    Heartbeat = 6,
+    /// Chat message replayed to a new member, control fields `code, content type, payload`
+    History = 7,
+    /// Memo for a member, kept by room owner while it is offline, control fields `target id, text`
+    Memo = 8,
+    /// What happened to a memo, control fields `target id, status`
+    Receipt = 9,
+    /// Message only the target sees, control fields `target id, text`
+    Private = 10,
+    /// Reply to an earlier message, control fields `target uid, text`
+    Answer = 11,
+    /// New text of our own message, control fields `target uid, text`
+    Edit = 12,
+    /// Delete our own message, control fields `target uid`
+    Delete = 13,
+    /// Emoji reaction to a message, control fields `target uid, emoji`
+    React = 14,
+    /// File offered to the room or one member, control fields `target id, size, crc32, name`
+    Offer = 15,
+    /// Ask for the chunks of a file from an index, control fields `target id, transfer uid, index`
+    Accept = 16,
+    /// Part of a file, control fields `target id, transfer uid, index, data`
+    Chunk = 17,
+    /// File received and checked, control fields `target id, transfer uid, ok`
+    Done = 18,
+    /// A member joined or left, relayed by room owner, control fields `kind, member id[, new id]`
+    Notice = 19,
+    /// Ask room owner to change our id, control fields `new id`
+    Nick = 20,
+    /// Typing, idle or away, relayed by room owner, control fields `state[, status text]`
+    Presence = 21,
 }
 
-impl From<u8> for Code {
-    fn from(code: u8) -> Self {
-        if code <= Code::None as u8 {
-            unsafe { mem::transmute(code) }
-        } else {
-            Code::None
+/// Codes free for extensions, they will never be given to a code of this protocol.
+pub const EXTENSION_CODES: RangeInclusive<u8> = 0x80..=0xff;
+
+impl Code {
+    /// Every code, in order.
+    pub const ALL: [Code; 22] = [
+        Code::None,
+        Code::Request,
+        Code::Reply,
+        Code::Message,
+        Code::Exit,
+        Code::Close,
+        Code::Heartbeat,
+        Code::History,
+        Code::Memo,
+        Code::Receipt,
+        Code::Private,
+        Code::Answer,
+        Code::Edit,
+        Code::Delete,
+        Code::React,
+        Code::Offer,
+        Code::Accept,
+        Code::Chunk,
+        Code::Done,
+        Code::Notice,
+        Code::Nick,
+        Code::Presence,
+    ];
+}
+
+/// A byte that is not the code of a frame.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct UnknownCode(pub u8);
+
+impl UnknownCode {
+    pub fn is_extension(&self) -> bool {
+        EXTENSION_CODES.contains(&self.0)
+    }
+}
+
+impl TryFrom<u8> for Code {
+    type Error = UnknownCode;
+
+    fn try_from(code: u8) -> Result<Self, Self::Error> {
+        Code::ALL
+            .iter()
+            .copied()
+            .find(|known| *known as u8 == code)
+            .ok_or(UnknownCode(code))
+    }
+}
+
+/// Result of a join request, first control field of `Code::Reply`.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ReplyResult {
+    /// Fields after it: reason, room owner id, member count
+    Accepted = 0,
+    /// Fields after it: reason
+    WrongKey,
+    /// Our leave is acknowledged
+    Left,
+    /// Our new id is refused, fields after it: reason
+    NickRefused,
+    /// Our id can't be used to join, fields after it: reason
+    Refused,
+}
+
+impl ReplyResult {
+    pub fn parse(result: u8) -> Option<ReplyResult> {
+        match result {
+            0 => Some(ReplyResult::Accepted),
+            1 => Some(ReplyResult::WrongKey),
+            2 => Some(ReplyResult::Left),
+            3 => Some(ReplyResult::NickRefused),
+            4 => Some(ReplyResult::Refused),
+            _ => None,
         }
     }
 }
 
-static mut IDENTITY: Identity = Identity::None;
-static mut KEY: String = String::new();
-static mut ROOM_OWNER_IP: String = String::new();
-pub const JOIN_SUCCESS: &'static str = "Success join room";
-pub const JOIN_FAILED: &'static str = "Error key";
-pub const EXIT_ROOM: &'static str = "Exit room";
+/// What happened to the member of a `Code::Notice`.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum NoticeKind {
+    Joined = 0,
+    Left,
+    /// Sent only to a member that just joined, once for every member already in the room
+    Present,
+    /// The member changed its id, given in a third field
+    Renamed,
+}
 
-pub fn set_identity(identity: Identity) {
-    unsafe {
-        IDENTITY = identity;
+/// How the text of a `Code::Message` sent as control fields `text, style` reads. Plain messages
+/// are sent as text.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Style {
+    Plain = 0,
+    /// Sent by `/me`, shown as `* alice waves`
+    Action,
+}
+
+impl Style {
+    pub fn parse(style: u8) -> Option<Style> {
+        match style {
+            0 => Some(Style::Plain),
+            1 => Some(Style::Action),
+            _ => None,
+        }
+    }
+}
+
+/// What a member is doing, first control field of `Code::Presence`.
+#[repr(u8)]
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Presence {
+    /// Back from idle or away
+    Active = 0,
+    /// Sent again every few seconds while typing, it fades when it is not
+    Typing,
+    Idle,
+    /// With the text of `/away` as second field
+    Away,
+}
+
+impl Presence {
+    pub fn parse(state: u8) -> Option<Presence> {
+        match state {
+            0 => Some(Presence::Active),
+            1 => Some(Presence::Typing),
+            2 => Some(Presence::Idle),
+            3 => Some(Presence::Away),
+            _ => None,
+        }
+    }
+}
+
+/// A room member as seen by the receive loop. For a joiner the only member is room owner.
+#[derive(Clone, Debug)]
+pub struct Member {
+    pub id: String,
+    pub addr: SocketAddr,
+    pub last_seen: Instant,
+    /// Last idle or away frame of the member, given to members that join later
+    pub presence: Option<protocol::Message>,
+    /// State and time of the last presence frame relayed
+    pub last_presence: Option<(Presence, Instant)>,
+}
+
+impl Member {
+    pub fn new(id: &str, addr: SocketAddr) -> Member {
+        Member {
+            id: id.to_string(),
+            addr,
+            last_seen: Instant::now(),
+            presence: None,
+            last_presence: None,
+        }
     }
 }
 
+lazy_static! {
+    static ref IDENTITY: Mutex<Identity> = Mutex::new(Identity::None);
+    static ref KEY: Mutex<String> = Mutex::new(String::new());
+    static ref ROOM_OWNER_IP: Mutex<String> = Mutex::new(String::new());
+}
+/// Texts shown for the room events, they never travel as commands.
+pub const JOIN_SUCCESS: &str = "Success join room";
+pub const JOIN_FAILED: &str = "Error key";
+pub const EXIT_ROOM: &str = "Exit room";
+pub const ROOM_CLOSED: &str = "Room closed";
+pub const NICK_TAKEN: &str = "This id is already taken";
+pub const NICK_INVALID: &str = "This id can't be used";
+/// How long `receive` blocks on the socket before checking whether it should stop.
+pub const RECEIVE_TIMEOUT: Duration = Duration::from_millis(200);
+/// Joiners send a heartbeat to room owner this often.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+/// Without any sign of life for this long, the other side is considered gone.
+pub const ALIVE_TIMEOUT: Duration = Duration::from_secs(6);
+/// Presence frames of a member in the same state closer than this are not relayed.
+pub const PRESENCE_INTERVAL: Duration = Duration::from_secs(1);
+
+static RUNNING: AtomicBool = AtomicBool::new(true);
+
+pub fn set_identity(identity: Identity) {
+    *IDENTITY.lock().unwrap() = identity;
+}
+
 pub fn get_identity() -> Identity {
-    unsafe { IDENTITY }
+    *IDENTITY.lock().unwrap()
 }
 
 pub fn get_key() -> String {
-    unsafe { KEY.clone() }
+    KEY.lock().unwrap().clone()
 }
 
 pub fn is_room_owner() -> bool {
-    unsafe { IDENTITY == Identity::RoomOwner }
+    get_identity() == Identity::RoomOwner
 }
 
 pub fn set_key(key: String) {
-    unsafe {
-        KEY = key.clone();
+    *KEY.lock().unwrap() = key;
+}
+
+/// Join request with the key of the room.
+pub fn join_request(key: &str) -> protocol::Message {
+    control(Code::Request, vec![key.as_bytes().to_vec()])
+}
+
+/// Chat message to the room, as text unless it has a style.
+pub fn chat_message(text: &str, style: Style) -> protocol::Message {
+    match style {
+        Style::Plain => protocol::Message::new(Code::Message as u8, text),
+        _ => control(
+            Code::Message,
+            vec![text.as_bytes().to_vec(), vec![style as u8]],
+        ),
     }
 }
 
-pub fn receive(socket: Arc<UdpSocket>, mess_que: Arc<Mutex<VecDeque<protocol::Message>>>) {
-    let mut buf = [0u8; 512];
-    let mut ips = Vec::<SocketAddr>::new();
+/// Text and style of a `Code::Message`, a style this version doesn't know reads plain.
+pub fn chat_text(message: &protocol::Message) -> (String, Style) {
+    match &message.payload {
+        protocol::Payload::Control(fields) => (
+            message.field_text(0),
+            fields
+                .get(1)
+                .and_then(|style| Style::parse(*style.first()?))
+                .unwrap_or(Style::Plain),
+        ),
+        _ => (message.text(), Style::Plain),
+    }
+}
 
-    loop {
-        if let Ok((size, addr)) = socket.recv_from(&mut buf) {
-            let message = match protocol::Message::parse(&buf[..size]) {
-                Ok(mes) => mes,
-                Err(_) => continue,
-            };
-            let code = Code::from(message.code);
-            match code {
-                Code::Request => {
-                    receive_request(&message, mess_que.clone(), addr, &mut ips, socket.clone());
-                }
-                Code::Reply => {
-                    receive_reply(&message, &mut ips, addr, mess_que.clone());
-                }
-                Code::Message => {
-                    receive_message(&message, mess_que.clone(), &ips, socket.clone());
-                }
-                Code::Exit => {
-                    receive_exit(&message, addr, mess_que.clone(), &mut ips, socket.clone());
+/// Ask room owner to call us `id` from now on.
+pub fn nick_request(id: &str) -> protocol::Message {
+    control(Code::Nick, vec![id.as_bytes().to_vec()])
+}
+
+/// Tell the room what we are doing, `status` is the reason of `/away`.
+pub fn presence_update(presence: Presence, status: &str) -> protocol::Message {
+    let mut fields = vec![vec![presence as u8]];
+    if !status.is_empty() {
+        fields.push(status.as_bytes().to_vec());
+    }
+    control(Code::Presence, fields)
+}
+
+/// Leave request, sent to room owner.
+pub fn exit_request() -> protocol::Message {
+    control(Code::Exit, Vec::new())
+}
+
+fn control(code: Code, fields: Vec<Vec<u8>>) -> protocol::Message {
+    protocol::Message::with_payload(code as u8, protocol::Payload::Control(fields))
+}
+
+fn reply(result: ReplyResult, reason: &str, room: Option<(&str, usize)>) -> protocol::Message {
+    let mut fields = vec![vec![result as u8], reason.as_bytes().to_vec()];
+    if let Some((owner, members)) = room {
+        fields.push(owner.as_bytes().to_vec());
+        fields.push((members as u16).to_be_bytes().to_vec());
+    }
+    control(Code::Reply, fields)
+}
+
+/// Tell members that the member `id` joined or left.
+fn notice(kind: NoticeKind, id: &str) -> protocol::Message {
+    control(Code::Notice, vec![vec![kind as u8], id.as_bytes().to_vec()])
+}
+
+/// Make `receive` return after its current wait on the socket.
+pub fn stop() {
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Receive loop of the room, run it on its own thread.
+///
+/// The socket should have a read timeout (see `RECEIVE_TIMEOUT`), otherwise `stop` only takes effect
+/// when the next datagram arrives.
+pub fn receive(socket: Arc<UdpSocket>, bus: Arc<EventBus>) {
+    let mut buf = [0u8; protocol::FRAME_MAX_LEN];
+    let mut members = Vec::<Member>::new();
+    let mut history = History::new();
+    let mut memos = MemoStore::new();
+
+    while is_running() {
+        match socket.recv_from(&mut buf) {
+            Ok((size, addr)) => {
+                let message = match protocol::Message::parse(&buf[..size]) {
+                    Ok(mes) => mes,
+                    Err(_) => continue,
+                };
+                protocol::observe(message.clock);
+                if let Some(index) = find_member(&addr, &members) {
+                    members[index].last_seen = Instant::now();
                 }
-                _ => {}
-            };
-            // buf.fill_with(Default::default);
-            buf.fill(Default::default());
+                let code = match Code::try_from(message.code) {
+                    Ok(code) => code,
+                    Err(_) => continue,
+                };
+                match code {
+                    Code::Request => {
+                        receive_request(
+                            &message,
+                            &bus,
+                            addr,
+                            &mut members,
+                            &mut history,
+                            &mut memos,
+                            socket.clone(),
+                        );
+                    }
+                    Code::Reply => {
+                        receive_reply(&message, &mut members, addr, &bus);
+                    }
+                    Code::Message | Code::Answer | Code::Edit | Code::Delete | Code::React => {
+                        receive_message(
+                            &message,
+                            addr,
+                            &bus,
+                            &members,
+                            &mut history,
+                            socket.clone(),
+                        );
+                    }
+                    Code::Exit => {
+                        receive_exit(&message, addr, &bus, &mut members, socket.clone());
+                    }
+                    Code::Close => {
+                        receive_close(&message, addr, &bus, &mut members);
+                    }
+                    Code::Heartbeat => {
+                        receive_heartbeat(addr, &bus, &members, socket.clone());
+                    }
+                    Code::History => {
+                        receive_history(&message, addr, &bus, &members);
+                    }
+                    Code::Memo => {
+                        receive_memo(&message, addr, &bus, &members, &mut memos, socket.clone());
+                    }
+                    Code::Receipt => {
+                        receive_receipt(&message, addr, &bus, &members);
+                    }
+                    Code::Private => {
+                        receive_private(&message, addr, &bus, &members, &mut memos, socket.clone());
+                    }
+                    Code::Notice => {
+                        receive_notice(&message, addr, &bus, &members);
+                    }
+                    Code::Presence => {
+                        receive_presence(&message, addr, &bus, &mut members, socket.clone());
+                    }
+                    Code::Nick => {
+                        receive_nick(
+                            &message,
+                            addr,
+                            &bus,
+                            &mut members,
+                            &mut memos,
+                            socket.clone(),
+                        );
+                    }
+                    Code::Offer | Code::Accept | Code::Chunk | Code::Done => {
+                        receive_transfer(&message, addr, &bus, &members, socket.clone());
+                    }
+                    _ => {}
+                };
+                buf.fill(Default::default());
+            }
+            Err(e) => match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {}
+                _ => bus.publish(Event::Error(e.to_string())),
+            },
         }
     }
 }
@@ -114,114 +471,672 @@ pub fn receive(socket: Arc<UdpSocket>, mess_que: Arc<Mutex<VecDeque<protocol::Me
 /// First compare the key recevied. If it is correct, client will join this ip to ip list and send new joiner to all ip.
 ///
 /// If is not, client will send this ip a message to notice the key is error.
+///
+/// A member coming back under the same id from the same IP, while its old address has gone silent,
+/// is taken as reconnecting: its address is updated and no join message is sent. Any other join
+/// under the id of a member is refused, see `check_join`.
+///
+/// A new member gets the message history before everyone is told it joined, then the memos
+/// left for it while it was offline and the receipts of its memos delivered since. The author of
+/// a memo delivered now gets its receipt when it is online, or on its next join.
 fn receive_request(
     message: &protocol::Message,
-    mess_que: Arc<Mutex<VecDeque<protocol::Message>>>,
+    bus: &EventBus,
     addr: SocketAddr,
-    ips: &mut Vec<SocketAddr>,
+    members: &mut Vec<Member>,
+    history: &mut History,
+    memos: &mut MemoStore,
     socket: Arc<UdpSocket>,
 ) {
     if is_room_owner() {
         // Compare key
-        if message.message == get_key() {
-            // Send to this ip with join success message
-            send_message_to(
-                &protocol::Message::new(Code::Reply as u8, &String::from(JOIN_SUCCESS)),
+        if message.fields().first().map(Vec::as_slice) == Some(get_key().as_bytes()) {
+            let id = &message.pro_id.id;
+            let local_addr = socket.local_addr().unwrap();
+            let joined = is_joined_room(&addr, members);
+            let returning = match check_join(id, &addr, members, &local_addr) {
+                _ if joined => None,
+                Ok(returning) => returning,
+                Err(reason) => {
+                    let _ =
+                        send_message_to(&reply(ReplyResult::Refused, reason, None), &addr, socket);
+                    return;
+                }
+            };
+            let count = members.len() + usize::from(!joined && returning.is_none());
+            // Send to this ip with join success message and the room info
+            let _ = send_message_to(
+                &reply(
+                    ReplyResult::Accepted,
+                    JOIN_SUCCESS,
+                    Some((&protocol::get_id().unwrap(), count)),
+                ),
                 &addr,
                 socket.clone(),
             );
-            // Let this ip join the ip list
-            if !is_joined_room(&addr, ips) {
-                let join_message = protocol::Message {
-                    code: Code::Message as u8,
-                    message: JOIN_SUCCESS.green().bold().to_string(),
-                    pro_id: protocol::ProtocolID {
-                        id: message.pro_id.id.clone(),
-                        protocol: protocol::get_protocol().unwrap(),
-                    },
-                };
-                push_into_ips(&addr, ips);
-                push_to_message_queue(&join_message, mess_que);
-                send_message_to_all(&join_message, ips, socket);
+            if joined {
+                return;
+            }
+            for member in members.iter().filter(|member| member.id != *id) {
+                let _ = send_message_to(
+                    &notice(NoticeKind::Present, &member.id),
+                    &addr,
+                    socket.clone(),
+                );
+                if let Some(presence) = &member.presence {
+                    let _ = send_message_to(presence, &addr, socket.clone());
+                }
+            }
+            if let Some(index) = returning {
+                members[index].addr = addr;
+                members[index].last_seen = Instant::now();
+            } else {
+                for old_message in history.recent() {
+                    let _ = send_message_to(&history::wrap(&old_message), &addr, socket.clone());
+                }
+                // Let this ip join the ip list and send the join notice to all ip
+                let join_message = notice(NoticeKind::Joined, id);
+                push_member(id, &addr, members);
+                bus.publish(Event::MemberJoined {
+                    id: message.pro_id.id.clone(),
+                });
+                send_message_to_all(&join_message, members, socket.clone());
+            }
+
+            memos.know(&message.pro_id.id);
+            for memo in memos.take(&message.pro_id.id) {
+                if memo.code == Code::Receipt as u8 {
+                    let _ = send_message_to(&memo, &addr, socket.clone());
+                    continue;
+                }
+                deliver_to(&memo, &addr, bus, socket.clone());
+                match find_online_by_id(&memo.pro_id.id, members, &socket) {
+                    Some(author) => {
+                        send_receipt(&memo, MemoStatus::Delivered, &author, bus, socket.clone())
+                    }
+                    None => {
+                        memos.queue_receipt(&memo.pro_id.id, &receipt(&memo, MemoStatus::Delivered))
+                    }
+                }
             }
-        // Send the join message to all ip
-        } else if message.message == EXIT_ROOM.to_string() {
-            receive_exit(message, addr, mess_que, ips, socket);
         } else {
-            send_message_to(
-                &protocol::Message::new(Code::Reply as u8, &String::from(JOIN_FAILED)),
+            let _ = send_message_to(
+                &reply(ReplyResult::WrongKey, JOIN_FAILED, None),
                 &addr,
-                socket.clone(),
+                socket,
             );
         }
     }
 }
 
+/// Why the member at `addr` can't join under `id`, or the index of the member it is coming back
+/// as: the same id from the same IP, while the old address has gone silent. Room owner itself is
+/// never silent, it can't be taken over.
+fn check_join(
+    id: &str,
+    addr: &SocketAddr,
+    members: &[Member],
+    local_addr: &SocketAddr,
+) -> Result<Option<usize>, &'static str> {
+    if !protocol::is_valid_id(id) {
+        return Err(NICK_INVALID);
+    }
+    let returning = members.iter().position(|member| {
+        member.id == id
+            && member.addr != *local_addr
+            && member.addr.ip() == addr.ip()
+            && member.last_seen.elapsed() > ALIVE_TIMEOUT
+    });
+    if returning.is_none() && members.iter().any(|member| member.id == id) {
+        return Err(NICK_TAKEN);
+    }
+    Ok(returning)
+}
+
 fn receive_reply(
     message: &protocol::Message,
-    ips: &mut Vec<SocketAddr>,
+    members: &mut Vec<Member>,
     addr: SocketAddr,
-    mess_que: Arc<Mutex<VecDeque<protocol::Message>>>,
+    bus: &EventBus,
 ) {
-    if !is_room_owner() {
-        if message.message == JOIN_SUCCESS {
-            push_into_ips(&addr, ips);
-            unsafe {
-                ROOM_OWNER_IP = addr.to_string();
-            }
+    if is_room_owner() {
+        return;
+    }
+    let fields = message.fields();
+    let text = |index: usize| message.field_text(index);
+    let result = fields
+        .first()
+        .and_then(|result| ReplyResult::parse(*result.first()?));
+    match result {
+        Some(ReplyResult::Accepted) => {
+            *ROOM_OWNER_IP.lock().unwrap() = addr.to_string();
+            members.clear();
+            push_member(&message.pro_id.id, &addr, members);
+            bus.publish(Event::JoinResult(JoinResult::Success {
+                owner: protocol::clean_id(&text(2)),
+                members: fields
+                    .get(3)
+                    .and_then(|count| protocol::field_u64(count))
+                    .unwrap_or(0) as usize,
+            }));
+        }
+        Some(ReplyResult::WrongKey) | Some(ReplyResult::Refused) => {
+            bus.publish(Event::JoinResult(JoinResult::Failed { reason: text(1) }));
         }
-        push_to_message_queue(message, mess_que);
+        Some(ReplyResult::Left) => bus.publish(Event::LeaveAck),
+        Some(ReplyResult::NickRefused) => bus.publish(Event::Error(text(1))),
+        None => {}
     }
 }
 
-/// If room owner receive message by Code::Message, it will send this message to all ip
+/// If room owner receive a chat message (a message, reply, edit, delete or reaction) from a member,
+/// it will send it to all ip under the id of that member.
 ///
-/// If not, it will push this message to message queue
+/// If not, it will publish it when it comes from room owner. Its text is never taken as a command.
 fn receive_message(
     message: &protocol::Message,
-    mess_que: Arc<Mutex<VecDeque<protocol::Message>>>,
-    ips: &Vec<SocketAddr>,
+    addr: SocketAddr,
+    bus: &EventBus,
+    members: &[Member],
+    history: &mut History,
     socket: Arc<UdpSocket>,
 ) {
-    push_to_message_queue(message, mess_que);
     if is_room_owner() {
-        send_message_to_all(message, ips, socket);
+        let message = match from_member(message, &addr, members) {
+            Some(message) => message,
+            None => return,
+        };
+        history.push(&message);
+        bus.publish(Event::ChatMessage(message.clone()));
+        send_message_to_all(&message, members, socket);
+        return;
+    }
+
+    if is_joined_room(&addr, members) {
+        bus.publish(Event::ChatMessage(message.clone()));
+    }
+}
+
+/// A member joined, left or changed its id, told by our room owner.
+fn receive_notice(
+    message: &protocol::Message,
+    addr: SocketAddr,
+    bus: &EventBus,
+    members: &[Member],
+) {
+    if is_room_owner() || !is_joined_room(&addr, members) {
+        return;
+    }
+    let fields = message.fields();
+    let id = match fields.get(1) {
+        Some(id) => protocol::clean_id(&String::from_utf8_lossy(id)),
+        None => return,
+    };
+    match fields.first().and_then(|kind| kind.first()) {
+        Some(kind) if *kind == NoticeKind::Joined as u8 => bus.publish(Event::MemberJoined { id }),
+        Some(kind) if *kind == NoticeKind::Left as u8 => bus.publish(Event::MemberLeft { id }),
+        Some(kind) if *kind == NoticeKind::Present as u8 => {
+            bus.publish(Event::MemberPresent { id })
+        }
+        Some(kind) if *kind == NoticeKind::Renamed as u8 => {
+            if let Some(new) = fields.get(2) {
+                bus.publish(Event::MemberRenamed {
+                    old: id,
+                    new: protocol::clean_id(&String::from_utf8_lossy(new)),
+                })
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Room owner changes the id of the member at `addr`, room owner itself included, if no other
+/// member has the new id. Everyone is told the new id, or the member why it is refused.
+fn receive_nick(
+    message: &protocol::Message,
+    addr: SocketAddr,
+    bus: &EventBus,
+    members: &mut [Member],
+    memos: &mut MemoStore,
+    socket: Arc<UdpSocket>,
+) {
+    if !is_room_owner() {
+        return;
+    }
+    let index = match find_member(&addr, members) {
+        Some(index) => index,
+        None => return,
+    };
+    let new = match message.fields().first() {
+        Some(new) => String::from_utf8_lossy(new).to_string(),
+        None => return,
+    };
+    let refused = if !protocol::is_valid_id(&new) {
+        Some(NICK_INVALID)
+    } else if members.iter().any(|member| member.id == new) {
+        Some(NICK_TAKEN)
+    } else {
+        None
+    };
+    let local_addr = socket.local_addr().unwrap();
+    if let Some(reason) = refused {
+        if addr == local_addr {
+            bus.publish(Event::Error(reason.to_string()));
+        } else {
+            let _ = send_message_to(
+                &reply(ReplyResult::NickRefused, reason, None),
+                &addr,
+                socket,
+            );
+        }
+        return;
     }
+
+    let old = std::mem::replace(&mut members[index].id, new.clone());
+    if let Some(presence) = members[index].presence.as_mut() {
+        presence.pro_id.id = new.clone();
+    }
+    memos.know(&new);
+    let renamed = control(
+        Code::Notice,
+        vec![
+            vec![NoticeKind::Renamed as u8],
+            old.as_bytes().to_vec(),
+            new.as_bytes().to_vec(),
+        ],
+    );
+    send_message_to_all(&renamed, members, socket);
+    bus.publish(Event::MemberRenamed { old, new });
 }
 
+/// Room owner passes the presence of a member, under its id in the room, to the others and keeps it
+/// for members that join later, unless it is typing. A frame in the same state as the last one is
+/// dropped if it comes too soon after it.
+///
+/// Joiners publish it.
+fn receive_presence(
+    message: &protocol::Message,
+    addr: SocketAddr,
+    bus: &EventBus,
+    members: &mut [Member],
+    socket: Arc<UdpSocket>,
+) {
+    let message = if is_room_owner() {
+        match from_member(message, &addr, members) {
+            Some(message) => message,
+            None => return,
+        }
+    } else if is_joined_room(&addr, members) {
+        message.clone()
+    } else {
+        return;
+    };
+    let fields = message.fields();
+    let presence = match fields
+        .first()
+        .and_then(|state| Presence::parse(*state.first()?))
+    {
+        Some(presence) => presence,
+        None => return,
+    };
+    let publish = || {
+        bus.publish(Event::Presence {
+            id: message.pro_id.id.clone(),
+            presence,
+            status: message.field_text(1),
+        })
+    };
+    if !is_room_owner() {
+        publish();
+        return;
+    }
+
+    let member = match find_member(&addr, members) {
+        Some(index) => &mut members[index],
+        None => return,
+    };
+    if let Some((last, at)) = member.last_presence {
+        if last == presence && at.elapsed() < PRESENCE_INTERVAL {
+            return;
+        }
+    }
+    member.last_presence = Some((presence, Instant::now()));
+    match presence {
+        Presence::Active => member.presence = None,
+        Presence::Idle | Presence::Away => member.presence = Some(message.clone()),
+        Presence::Typing => {}
+    }
+    publish();
+    let local_addr = socket.local_addr().unwrap();
+    for member in members.iter() {
+        if member.addr != addr && member.addr != local_addr {
+            let _ = send_message_to(&message, &member.addr, socket.clone());
+        }
+    }
+}
+
+/// Room owner receive exit from a member: remove it, tell the others and acknowledge the leave.
+///
+/// When the exit comes from room owner itself, the room is closed for every member.
 fn receive_exit(
     message: &protocol::Message,
     addr: SocketAddr,
-    mess_que: Arc<Mutex<VecDeque<protocol::Message>>>,
-    ips: &mut Vec<SocketAddr>,
+    bus: &EventBus,
+    members: &mut Vec<Member>,
     socket: Arc<UdpSocket>,
 ) {
-    let mut message = message.clone();
-    message.code = Code::Message as u8;
-    message.message = EXIT_ROOM.clone().red().bold().to_string();
-    push_to_message_queue(&message, mess_que);
-    if is_room_owner() && addr != socket.local_addr().unwrap() {
-        ips.remove(find_ip(&addr, &ips).unwrap());
-        send_message_to_all(&message, ips, socket);
+    let id = message.pro_id.id.clone();
+    if !is_room_owner() {
+        if is_joined_room(&addr, members) {
+            ROOM_OWNER_IP.lock().unwrap().clear();
+            members.clear();
+            bus.publish(Event::MemberLeft { id });
+        }
+        return;
+    }
+
+    if addr == socket.local_addr().unwrap() {
+        send_message_to_all(
+            &control(Code::Close, vec![ROOM_CLOSED.as_bytes().to_vec()]),
+            members,
+            socket,
+        );
+        members.clear();
+        bus.publish(Event::LeaveAck);
+        return;
+    }
+
+    if let Some(index) = find_member(&addr, members) {
+        let member = members.remove(index);
+        bus.publish(Event::MemberLeft { id });
+        send_message_to_all(
+            &notice(NoticeKind::Left, &member.id),
+            members,
+            socket.clone(),
+        );
+    }
+    let _ = send_message_to(&reply(ReplyResult::Left, "", None), &addr, socket);
+}
+
+/// Room owner has closed the room. Only taken into account when it comes from our room owner.
+fn receive_close(
+    message: &protocol::Message,
+    addr: SocketAddr,
+    bus: &EventBus,
+    members: &mut Vec<Member>,
+) {
+    if is_room_owner() || !is_joined_room(&addr, members) {
+        return;
+    }
+    ROOM_OWNER_IP.lock().unwrap().clear();
+    members.clear();
+    bus.publish(Event::RoomClosed {
+        id: message.pro_id.id.clone(),
+    });
+}
+
+/// Message sent before we joined, replayed by room owner.
+fn receive_history(
+    message: &protocol::Message,
+    addr: SocketAddr,
+    bus: &EventBus,
+    members: &[Member],
+) {
+    if !is_room_owner() && is_joined_room(&addr, members) {
+        if let Some(message) = history::unwrap(message) {
+            bus.publish(Event::History(message));
+        }
+    }
+}
+
+/// Room owner passes the memo to its target, or keeps it until the target joins again, under the id
+/// its author has in the room. The author is told what happened to it.
+///
+/// Joiners receive memos from room owner.
+fn receive_memo(
+    message: &protocol::Message,
+    addr: SocketAddr,
+    bus: &EventBus,
+    members: &[Member],
+    memos: &mut MemoStore,
+    socket: Arc<UdpSocket>,
+) {
+    if !is_joined_room(&addr, members) {
+        return;
+    }
+    if !is_room_owner() {
+        publish_memo(message, bus);
+        return;
+    }
+
+    let message = &match from_member(message, &addr, members) {
+        Some(message) => message,
+        None => return,
+    };
+    let (target, _) = message.split_target();
+    let status = match find_online_by_id(&target, members, &socket) {
+        Some(target_addr) => {
+            deliver_to(message, &target_addr, bus, socket.clone());
+            MemoStatus::Delivered
+        }
+        None if memos.is_known(&target) => memos.queue(message),
+        None => MemoStatus::Unknown,
+    };
+    send_receipt(message, status, &addr, bus, socket);
+}
+
+/// Room owner passes a private message only to its target, under the id its author has in the
+/// room. If the target is offline, the message is kept as a memo and the author gets the memo
+/// receipt.
+///
+/// Joiners receive private messages from room owner.
+fn receive_private(
+    message: &protocol::Message,
+    addr: SocketAddr,
+    bus: &EventBus,
+    members: &[Member],
+    memos: &mut MemoStore,
+    socket: Arc<UdpSocket>,
+) {
+    if !is_joined_room(&addr, members) {
+        return;
+    }
+    if !is_room_owner() {
+        publish_private(message, bus);
+        return;
+    }
+
+    let message = &match from_member(message, &addr, members) {
+        Some(message) => message,
+        None => return,
+    };
+    let (target, _) = message.split_target();
+    match find_online_by_id(&target, members, &socket) {
+        Some(target_addr) if target_addr == socket.local_addr().unwrap() => {
+            publish_private(message, bus);
+        }
+        Some(target_addr) => {
+            let _ = send_message_to(message, &target_addr, socket);
+        }
+        None => {
+            let mut memo = message.clone();
+            memo.code = Code::Memo as u8;
+            let status = if memos.is_known(&target) {
+                memos.queue(&memo)
+            } else {
+                MemoStatus::Unknown
+            };
+            send_receipt(&memo, status, &addr, bus, socket);
+        }
     }
 }
 
-fn push_to_message_queue(
+/// Room owner passes file transfer frames, under the id their sender has in the room, to the target
+/// in their first control field, or to every other member when it is empty. Nothing is kept for
+/// offline members, the transfer is tried again later.
+///
+/// Joiners hand the frames to the transfer thread.
+fn receive_transfer(
     message: &protocol::Message,
-    mess_que: Arc<Mutex<VecDeque<protocol::Message>>>,
+    addr: SocketAddr,
+    bus: &EventBus,
+    members: &[Member],
+    socket: Arc<UdpSocket>,
+) {
+    if !is_joined_room(&addr, members) {
+        return;
+    }
+    if !is_room_owner() {
+        bus.publish(Event::Transfer(message.clone()));
+        return;
+    }
+
+    let message = &match from_member(message, &addr, members) {
+        Some(message) => message,
+        None => return,
+    };
+    let local_addr = socket.local_addr().unwrap();
+    let target = match message.fields().first() {
+        Some(target) => String::from_utf8_lossy(target).to_string(),
+        None => return,
+    };
+    if target.is_empty() {
+        if addr != local_addr {
+            bus.publish(Event::Transfer(message.clone()));
+        }
+        for member in members.iter() {
+            if member.addr != addr && member.addr != local_addr {
+                let _ = send_message_to(message, &member.addr, socket.clone());
+            }
+        }
+        return;
+    }
+    match find_online_by_id(&target, members, &socket) {
+        Some(target_addr) if target_addr == local_addr => {
+            bus.publish(Event::Transfer(message.clone()));
+        }
+        Some(target_addr) => {
+            let _ = send_message_to(message, &target_addr, socket);
+        }
+        None => {}
+    }
+}
+
+fn publish_private(message: &protocol::Message, bus: &EventBus) {
+    let (_, text) = message.split_target();
+    bus.publish(Event::Private {
+        from: message.pro_id.id.clone(),
+        text,
+    });
+}
+
+/// What room owner did with a memo we wrote.
+fn receive_receipt(
+    message: &protocol::Message,
+    addr: SocketAddr,
+    bus: &EventBus,
+    members: &[Member],
+) {
+    if !is_room_owner() && is_joined_room(&addr, members) {
+        publish_receipt(message, bus);
+    }
+}
+
+fn publish_memo(message: &protocol::Message, bus: &EventBus) {
+    let (_, text) = message.split_target();
+    bus.publish(Event::Memo {
+        from: message.pro_id.id.clone(),
+        text,
+    });
+}
+
+fn publish_receipt(message: &protocol::Message, bus: &EventBus) {
+    let status = message
+        .fields()
+        .get(1)
+        .and_then(|status| MemoStatus::parse(*status.first()?));
+    if let Some(status) = status {
+        bus.publish(Event::MemoReceipt {
+            to: message.field_text(0),
+            status,
+        });
+    }
+}
+
+fn receipt(memo: &protocol::Message, status: MemoStatus) -> protocol::Message {
+    let (target, _) = memo.split_target();
+    control(Code::Receipt, vec![target.into_bytes(), vec![status as u8]])
+}
+
+/// Tell the author at `addr` what happened to its memo.
+fn send_receipt(
+    memo: &protocol::Message,
+    status: MemoStatus,
+    addr: &SocketAddr,
+    bus: &EventBus,
+    socket: Arc<UdpSocket>,
 ) {
-    let mut lock_messages = mess_que.lock().unwrap();
-    lock_messages.push_back(message.clone());
+    let receipt = receipt(memo, status);
+    if *addr == socket.local_addr().unwrap() {
+        publish_receipt(&receipt, bus);
+    } else {
+        let _ = send_message_to(&receipt, addr, socket);
+    }
+}
+
+/// Send a memo to the member at `addr`, room owner itself included.
+fn deliver_to(memo: &protocol::Message, addr: &SocketAddr, bus: &EventBus, socket: Arc<UdpSocket>) {
+    if *addr == socket.local_addr().unwrap() {
+        publish_memo(memo, bus);
+    } else {
+        let _ = send_message_to(memo, addr, socket);
+    }
 }
 
-pub fn send_message_to(message: &protocol::Message, addr: &SocketAddr, socket: Arc<UdpSocket>) {
-    socket.send_to(&message.to_buf(), addr).unwrap();
+/// Address of the member with the id, if it has shown a sign of life recently.
+fn find_online_by_id(id: &str, members: &[Member], socket: &UdpSocket) -> Option<SocketAddr> {
+    let local_addr = socket.local_addr().unwrap();
+    members
+        .iter()
+        .find(|member| {
+            member.id == id
+                && (member.addr == local_addr || member.last_seen.elapsed() <= ALIVE_TIMEOUT)
+        })
+        .map(|member| member.addr)
 }
 
-fn send_message_to_all(message: &protocol::Message, ips: &Vec<SocketAddr>, socket: Arc<UdpSocket>) {
-    for ip in ips.iter() {
-        if socket.local_addr().unwrap().to_string() != ip.to_string() {
-            send_message_to(&message, ip, socket.clone());
+/// Room owner answers the heartbeat of its members, joiners take the answer as a sign of life.
+///
+/// Heartbeats from unknown addresses get no answer, so the sender notices it is not in the room.
+fn receive_heartbeat(addr: SocketAddr, bus: &EventBus, members: &[Member], socket: Arc<UdpSocket>) {
+    if !is_joined_room(&addr, members) {
+        return;
+    }
+    if is_room_owner() {
+        let _ = send_message_to(
+            &protocol::Message::new(Code::Heartbeat as u8, ""),
+            &addr,
+            socket,
+        );
+    } else {
+        bus.publish(Event::Heartbeat);
+    }
+}
+
+/// Send a frame to `addr`. It fails while the network is down, like a lost datagram it is then for
+/// heartbeats and retries to find out, so relays don't look at the error.
+pub fn send_message_to(
+    message: &protocol::Message,
+    addr: &SocketAddr,
+    socket: Arc<UdpSocket>,
+) -> io::Result<()> {
+    socket.send_to(&message.to_buf(), addr).map(|_| ())
+}
+
+fn send_message_to_all(message: &protocol::Message, members: &[Member], socket: Arc<UdpSocket>) {
+    for member in members.iter() {
+        if socket.local_addr().unwrap() != member.addr {
+            let _ = send_message_to(message, &member.addr, socket.clone());
         }
     }
 }
@@ -235,33 +1150,95 @@ pub fn get_local_addr() -> io::Result<SocketAddr> {
     socket.local_addr()
 }
 
-fn push_into_ips(ip: &SocketAddr, ips: &mut Vec<SocketAddr>) -> bool {
-    if is_joined_room(ip, ips) {
+fn push_member(id: &str, ip: &SocketAddr, members: &mut Vec<Member>) -> bool {
+    if find_member(ip, members).is_some() {
         return false;
     }
-    ips.push(ip.clone());
+    members.push(Member::new(id, *ip));
     true
 }
 
-fn is_joined_room(ip: &SocketAddr, ips: &Vec<SocketAddr>) -> bool {
+fn is_joined_room(ip: &SocketAddr, members: &[Member]) -> bool {
     if !is_room_owner() {
-        unsafe {
-            return ROOM_OWNER_IP == ip.to_string();
-        }
+        return *ROOM_OWNER_IP.lock().unwrap() == ip.to_string();
     }
-    for i_ip in ips.iter() {
-        if ip.to_string() == i_ip.to_string() {
-            return true;
-        }
-    }
-    false
+    find_member(ip, members).is_some()
+}
+
+fn find_member(ip: &SocketAddr, members: &[Member]) -> Option<usize> {
+    members.iter().position(|member| member.addr == *ip)
+}
+
+/// The frame under the id the member at `ip` has in the room, whatever id it carries, so nobody can
+/// write as someone else. `None` if `ip` is not a member.
+fn from_member(
+    message: &protocol::Message,
+    ip: &SocketAddr,
+    members: &[Member],
+) -> Option<protocol::Message> {
+    let index = find_member(ip, members)?;
+    let mut message = message.clone();
+    message.pro_id.id = members[index].id.clone();
+    Some(message)
 }
 
-fn find_ip(ip: &SocketAddr, ips: &Vec<SocketAddr>) -> Option<usize> {
-    for index in 0..ips.len() {
-        if ip.to_string() == ips[index].to_string() {
-            return Some(index);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([192, 168, 1, 2], port))
+    }
+
+    #[test]
+    fn frames_take_the_id_of_their_member() {
+        let members = vec![Member::new("alice", addr(1)), Member::new("bob", addr(2))];
+        let mut message = protocol::Message::new(Code::Message as u8, "hello");
+        message.pro_id.id = String::from("alice");
+
+        let relayed = from_member(&message, &addr(2), &members).unwrap();
+        assert_eq!(relayed.pro_id.id, "bob");
+        assert_eq!(relayed.uid, message.uid);
+        assert!(from_member(&message, &addr(3), &members).is_none());
+    }
+
+    #[test]
+    fn ids_of_members_are_not_taken() {
+        let owner = addr(1);
+        let silent = Instant::now().checked_sub(ALIVE_TIMEOUT * 2).unwrap();
+        let mut members = vec![Member::new("alice", owner), Member::new("bob", addr(2))];
+        members[0].last_seen = silent;
+        let carol = Member {
+            last_seen: silent,
+            ..Member::new("carol", addr(3))
+        };
+        members.push(carol);
+        let elsewhere = SocketAddr::from(([192, 168, 1, 9], 4));
+
+        assert_eq!(check_join("dave", &addr(4), &members, &owner), Ok(None));
+        assert_eq!(
+            check_join("bob", &addr(4), &members, &owner),
+            Err(NICK_TAKEN)
+        );
+        // Room owner can't be taken over, even from its own IP
+        assert_eq!(
+            check_join("alice", &addr(4), &members, &owner),
+            Err(NICK_TAKEN)
+        );
+        // A silent member comes back from its IP only
+        assert_eq!(check_join("carol", &addr(4), &members, &owner), Ok(Some(2)));
+        assert_eq!(
+            check_join("carol", &elsewhere, &members, &owner),
+            Err(NICK_TAKEN)
+        );
+
+        for id in ["", "da ve", "dave\n", "\u{1b}[2J"] {
+            assert_eq!(
+                check_join(id, &addr(4), &members, &owner),
+                Err(NICK_INVALID),
+                "{:?}",
+                id
+            );
         }
     }
-    None
 }
@@ -0,0 +1,753 @@
+use std::{
+    convert::TryFrom,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    net::{SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::prelude::*;
+use event::{Event, EventBus, EventKind};
+use server::Code;
+
+/// Bytes of a file in one chunk, so a chunk frame fits `protocol::FRAME_MAX_LEN` even when both ids
+/// are as long as they can be. The other fields are the target id, transfer uid and index.
+pub const CHUNK_LEN: usize = protocol::FRAME_MAX_LEN
+    - protocol::HEADER_MAX_LEN
+    - 4 * protocol::FIELD_LEN_LEN
+    - protocol::ID_MAX_LEN
+    - protocol::UID_LEN
+    - INDEX_LEN;
+/// Bytes of the index of a chunk
+const INDEX_LEN: usize = 8;
+/// Chunks asked for at once.
+const WINDOW: u64 = 16;
+/// Without a chunk for this long, the missing ones are asked for again.
+const CHUNK_TIMEOUT: Duration = Duration::from_millis(500);
+/// Asking this many times in a row without an answer stops the transfer.
+const RETRY_MAX: u32 = 10;
+/// How long the transfer thread waits for a frame before looking at requests and timeouts.
+const TICK: Duration = Duration::from_millis(50);
+/// Offers kept to be accepted later.
+const OFFERS_MAX: usize = 50;
+
+/// What happened to a file transfer, told to the UI.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransferState {
+    /// `from` offers a file we may accept.
+    Offered {
+        from: String,
+        size: u64,
+    },
+    /// We offer a file to `to`, or to the room when it is empty.
+    Sending {
+        to: String,
+        size: u64,
+    },
+    Receiving {
+        received: u64,
+        size: u64,
+    },
+    /// Saved and checked.
+    Received {
+        path: PathBuf,
+    },
+    /// `by` has got our file, `ok` is false if its checksum did not match.
+    Delivered {
+        by: String,
+        ok: bool,
+    },
+    Failed(String),
+}
+
+/// What the UI asks the transfer thread to do.
+enum Request {
+    Send { path: PathBuf, to: Option<String> },
+    Accept { reference: String },
+}
+
+struct Offer {
+    id: u64,
+    /// Id of the member offering the file
+    from: String,
+    name: String,
+    size: u64,
+    crc: u32,
+}
+
+struct Incoming {
+    offer: Offer,
+    file: File,
+    part: PathBuf,
+    /// Index of the next chunk to write
+    next: u64,
+    /// Chunks up to this index have been asked for
+    until: u64,
+    crc: u32,
+    last_chunk: Instant,
+    retries: u32,
+}
+
+lazy_static! {
+    static ref SEND_ADDR: Mutex<Option<SocketAddr>> = Mutex::new(None);
+    static ref REQUESTS: Mutex<Option<Sender<Request>>> = Mutex::new(None);
+}
+
+/// Start the transfer thread. It stops with the receive thread, see `server::stop`.
+pub fn start(bus: Arc<EventBus>, socket: Arc<UdpSocket>) -> JoinHandle<()> {
+    let frames = bus.subscribe(&[EventKind::Transfer]);
+    let (sender, requests) = mpsc::channel();
+    *REQUESTS.lock().unwrap() = Some(sender);
+    thread::spawn(move || {
+        let mut worker = Worker {
+            bus,
+            socket,
+            outgoing: Vec::new(),
+            offers: Vec::new(),
+            incoming: Vec::new(),
+        };
+        worker.run(frames, requests);
+    })
+}
+
+/// Room owner address the frames go to, `None` while not in a room.
+pub fn set_send_addr(send_addr: Option<SocketAddr>) {
+    *SEND_ADDR.lock().unwrap() = send_addr;
+}
+
+/// Offer the file to the member `to`, or to the whole room.
+pub fn send(path: &Path, to: Option<&str>) {
+    request(Request::Send {
+        path: path.to_path_buf(),
+        to: to.map(str::to_string),
+    });
+}
+
+/// Accept the offer whose id ends with `reference`, resuming from what is already downloaded.
+pub fn accept(reference: &str) {
+    request(Request::Accept {
+        reference: reference.trim_start_matches('#').to_lowercase(),
+    });
+}
+
+fn request(request: Request) {
+    if let Some(sender) = REQUESTS.lock().unwrap().as_ref() {
+        let _ = sender.send(request);
+    }
+}
+
+struct Worker {
+    bus: Arc<EventBus>,
+    socket: Arc<UdpSocket>,
+    /// Our offers with the file they send
+    outgoing: Vec<(u64, PathBuf)>,
+    offers: Vec<Offer>,
+    incoming: Vec<Incoming>,
+}
+
+impl Worker {
+    fn run(&mut self, frames: Receiver<Event>, requests: Receiver<Request>) {
+        while server::is_running() {
+            match frames.recv_timeout(TICK) {
+                Ok(Event::Transfer(message)) => self.frame(&message),
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            while let Ok(request) = requests.try_recv() {
+                match request {
+                    Request::Send { path, to } => self.offer(&path, to),
+                    Request::Accept { reference } => self.accept(&reference),
+                }
+            }
+            self.tick();
+        }
+    }
+
+    /// Handle a frame, its first control field is the target and is skipped.
+    fn frame(&mut self, message: &protocol::Message) {
+        let from = message.pro_id.id.clone();
+        let fields = message.fields().get(1..).unwrap_or_default();
+        let number = |index: usize| {
+            fields
+                .get(index)
+                .and_then(|field| protocol::field_u64(field))
+        };
+        match (Code::try_from(message.code), number(0), number(1)) {
+            (Ok(Code::Offer), Some(size), Some(crc))
+                if Some(&from) != protocol::get_id().as_ref() =>
+            {
+                // Field 2 after the target, read clean of control chars
+                let name = message.field_text(3);
+                self.offered(message.uid, from, &name, size, crc as u32);
+            }
+            (Ok(Code::Accept), Some(id), Some(index)) => self.send_chunks(id, index, &from),
+            (Ok(Code::Chunk), Some(id), Some(index)) => {
+                if let Some(data) = fields.get(2) {
+                    self.chunk(id, index, data);
+                }
+            }
+            (Ok(Code::Done), Some(id), Some(ok)) => {
+                if let Some(name) = self.outgoing_name(id) {
+                    self.publish(
+                        id,
+                        &name,
+                        TransferState::Delivered {
+                            by: from.clone(),
+                            ok: ok == 1,
+                        },
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn offer(&mut self, path: &Path, to: Option<String>) {
+        let name = file_name(path);
+        let (size, crc) = match checksum(path) {
+            Ok(sum) => sum,
+            Err(e) => {
+                return self.publish(0, &name, TransferState::Failed(e.to_string()));
+            }
+        };
+        let to = to.unwrap_or_default();
+        let offer = control(
+            Code::Offer,
+            &to,
+            vec![
+                size.to_be_bytes().to_vec(),
+                crc.to_be_bytes().to_vec(),
+                name.clone().into_bytes(),
+            ],
+        );
+        if self.send_frame(&offer) {
+            self.outgoing.push((offer.uid, path.to_path_buf()));
+            self.publish(offer.uid, &name, TransferState::Sending { to, size });
+        } else {
+            self.publish(0, &name, not_connected());
+        }
+    }
+
+    fn offered(&mut self, id: u64, from: String, name: &str, size: u64, crc: u32) {
+        let offer = Offer {
+            id,
+            from,
+            name: file_name(Path::new(name)),
+            size,
+            crc,
+        };
+        self.publish(
+            id,
+            &offer.name,
+            TransferState::Offered {
+                from: offer.from.clone(),
+                size: offer.size,
+            },
+        );
+        self.offers.push(offer);
+        if self.offers.len() > OFFERS_MAX {
+            self.offers.remove(0);
+        }
+    }
+
+    fn accept(&mut self, reference: &str) {
+        let index = match self.offers.iter().rposition(|offer| {
+            !reference.is_empty() && format!("{:016x}", offer.id).ends_with(reference)
+        }) {
+            Some(index) => index,
+            None => {
+                return self.publish(
+                    0,
+                    reference,
+                    TransferState::Failed(String::from("no such offer")),
+                )
+            }
+        };
+        if self
+            .incoming
+            .iter()
+            .any(|incoming| incoming.offer.id == self.offers[index].id)
+        {
+            return;
+        }
+        let offer = self.offers.remove(index);
+        match open_part(&config::get().transfer_dir, &offer) {
+            Ok((file, part, next, crc)) => {
+                let mut incoming = Incoming {
+                    offer,
+                    file,
+                    part,
+                    next,
+                    until: next,
+                    crc,
+                    last_chunk: Instant::now(),
+                    retries: 0,
+                };
+                if incoming.next >= chunk_count(incoming.offer.size) {
+                    self.finish(incoming);
+                } else {
+                    self.ask(&mut incoming);
+                    self.incoming.push(incoming);
+                }
+            }
+            Err(e) => {
+                let (id, name) = (offer.id, offer.name.clone());
+                self.offers.push(offer);
+                self.publish(id, &name, TransferState::Failed(e.to_string()));
+            }
+        }
+    }
+
+    /// Send the next window of chunks from `index` to the member that asked for them. An index
+    /// past the end of the file is ignored.
+    fn send_chunks(&mut self, id: u64, index: u64, to: &str) {
+        let path = match self.outgoing.iter().find(|(offer, _)| *offer == id) {
+            Some((_, path)) => path.clone(),
+            None => return,
+        };
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+        let count = match file.metadata() {
+            Ok(meta) => chunk_count(meta.len()),
+            Err(_) => return,
+        };
+        let start = match index.checked_mul(CHUNK_LEN as u64) {
+            Some(start) if index < count => start,
+            _ => return,
+        };
+        if file.seek(SeekFrom::Start(start)).is_err() {
+            return;
+        }
+        let mut data = [0u8; CHUNK_LEN];
+        for index in index..index.saturating_add(WINDOW).min(count) {
+            let size = match read_chunk(&mut file, &mut data) {
+                Ok(0) | Err(_) => break,
+                Ok(size) => size,
+            };
+            let chunk = control(
+                Code::Chunk,
+                to,
+                vec![
+                    id.to_be_bytes().to_vec(),
+                    index.to_be_bytes().to_vec(),
+                    data[..size].to_vec(),
+                ],
+            );
+            self.send_frame(&chunk);
+        }
+    }
+
+    /// Write the chunk if it is the next one. An index past the end of the offer is ignored.
+    fn chunk(&mut self, id: u64, index: u64, data: &[u8]) {
+        let position = match self.incoming.iter().position(|incoming| {
+            incoming.offer.id == id && index < chunk_count(incoming.offer.size)
+        }) {
+            Some(position) => position,
+            None => return,
+        };
+        let incoming = &mut self.incoming[position];
+        if index != incoming.next {
+            // A chunk went missing, ask again from it once the window is over
+            if index.checked_add(1) == Some(incoming.until) {
+                let mut incoming = self.incoming.remove(position);
+                self.ask(&mut incoming);
+                self.incoming.push(incoming);
+            }
+            return;
+        }
+        if let Err(e) = incoming.file.write_all(data) {
+            let incoming = self.incoming.remove(position);
+            return self.publish(
+                id,
+                &incoming.offer.name,
+                TransferState::Failed(e.to_string()),
+            );
+        }
+        incoming.crc = crc32(incoming.crc, data);
+        incoming.next += 1;
+        incoming.last_chunk = Instant::now();
+        incoming.retries = 0;
+
+        if incoming.next >= chunk_count(incoming.offer.size) {
+            let incoming = self.incoming.remove(position);
+            self.finish(incoming);
+        } else if incoming.next == incoming.until {
+            let mut incoming = self.incoming.remove(position);
+            self.ask(&mut incoming);
+            self.incoming.push(incoming);
+        }
+    }
+
+    /// Ask the sender for the window of chunks from the next one we need, and show the progress.
+    fn ask(&self, incoming: &mut Incoming) {
+        incoming.until = (incoming.next + WINDOW).min(chunk_count(incoming.offer.size));
+        incoming.last_chunk = Instant::now();
+        let accept = control(
+            Code::Accept,
+            &incoming.offer.from,
+            vec![
+                incoming.offer.id.to_be_bytes().to_vec(),
+                incoming.next.to_be_bytes().to_vec(),
+            ],
+        );
+        if !self.send_frame(&accept) {
+            incoming.retries = RETRY_MAX;
+        }
+        self.publish(
+            incoming.offer.id,
+            &incoming.offer.name,
+            TransferState::Receiving {
+                received: (incoming.next * CHUNK_LEN as u64).min(incoming.offer.size),
+                size: incoming.offer.size,
+            },
+        );
+    }
+
+    /// Check the downloaded file, keep it under its own name and tell the sender.
+    fn finish(&mut self, incoming: Incoming) {
+        let Incoming {
+            offer,
+            file,
+            part,
+            crc,
+            ..
+        } = incoming;
+        drop(file);
+        let result = if crc == offer.crc {
+            let path = free_path(&config::get().transfer_dir, &offer.name);
+            fs::rename(&part, &path).map(|_| path)
+        } else {
+            let _ = fs::remove_file(&part);
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checksum mismatch",
+            ))
+        };
+        let done = control(
+            Code::Done,
+            &offer.from,
+            vec![offer.id.to_be_bytes().to_vec(), vec![result.is_ok() as u8]],
+        );
+        self.send_frame(&done);
+        let state = match result {
+            Ok(path) => TransferState::Received { path },
+            Err(e) => TransferState::Failed(e.to_string()),
+        };
+        self.publish(offer.id, &offer.name, state);
+    }
+
+    /// Ask again for the chunks of stalled transfers, give up after `RETRY_MAX` tries.
+    fn tick(&mut self) {
+        let mut index = 0;
+        while index < self.incoming.len() {
+            if self.incoming[index].last_chunk.elapsed() < CHUNK_TIMEOUT {
+                index += 1;
+                continue;
+            }
+            let mut incoming = self.incoming.remove(index);
+            incoming.retries += 1;
+            if incoming.retries > RETRY_MAX {
+                let Incoming { offer, .. } = incoming;
+                self.publish(
+                    offer.id,
+                    &offer.name,
+                    TransferState::Failed(String::from("sender is not answering")),
+                );
+                self.offers.push(offer);
+                continue;
+            }
+            self.ask(&mut incoming);
+            self.incoming.insert(index, incoming);
+            index += 1;
+        }
+    }
+
+    fn outgoing_name(&self, id: u64) -> Option<String> {
+        self.outgoing
+            .iter()
+            .find(|(offer, _)| *offer == id)
+            .map(|(_, path)| file_name(path))
+    }
+
+    /// Send a frame to room owner, false if we are not in a room.
+    fn send_frame(&self, message: &protocol::Message) -> bool {
+        match *SEND_ADDR.lock().unwrap() {
+            Some(send_addr) => {
+                // A frame that can't go is sent again like a lost one
+                let _ = server::send_message_to(message, &send_addr, self.socket.clone());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn publish(&self, id: u64, name: &str, state: TransferState) {
+        self.bus.publish(Event::TransferStatus {
+            id,
+            name: name.to_string(),
+            state,
+        });
+    }
+}
+
+fn not_connected() -> TransferState {
+    TransferState::Failed(String::from("not connected to room owner"))
+}
+
+/// Control frame for the member `to`, or for the whole room when `to` is empty.
+fn control(code: Code, to: &str, fields: Vec<Vec<u8>>) -> protocol::Message {
+    let mut payload = vec![to.as_bytes().to_vec()];
+    payload.extend(fields);
+    protocol::Message::with_payload(code as u8, protocol::Payload::Control(payload))
+}
+
+fn chunk_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_LEN as u64)
+}
+
+/// Name of the file without its directories, so an offer can't write outside the download directory.
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .filter(|name| !name.is_empty() && name != "..")
+        .unwrap_or_else(|| String::from("file"))
+}
+
+/// Size and crc32 of the file.
+fn checksum(path: &Path) -> io::Result<(u64, u32)> {
+    let mut file = File::open(path)?;
+    let mut data = [0u8; 8192];
+    let (mut size, mut crc) = (0, 0);
+    loop {
+        match file.read(&mut data)? {
+            0 => return Ok((size, crc)),
+            read => {
+                size += read as u64;
+                crc = crc32(crc, &data[..read]);
+            }
+        }
+    }
+}
+
+/// Open the partly downloaded file of the offer in `dir`, keeping the whole chunks already there,
+/// or all of them if the file is complete.
+///
+/// Returns the file, its path, the index of the next chunk and the crc32 of what is kept.
+fn open_part(dir: &Path, offer: &Offer) -> io::Result<(File, PathBuf, u64, u32)> {
+    fs::create_dir_all(dir)?;
+    let part = dir.join(format!("{}.{:08x}.part", offer.name, offer.crc));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&part)?;
+    // The last chunk may be short, a part as long as the file has all of them
+    let len = file.metadata()?.len();
+    let next = if len >= offer.size {
+        chunk_count(offer.size)
+    } else {
+        len / CHUNK_LEN as u64
+    };
+    let kept = (next * CHUNK_LEN as u64).min(offer.size);
+    file.set_len(kept)?;
+
+    let mut crc = 0;
+    let mut data = [0u8; 8192];
+    let mut left = kept;
+    while left > 0 {
+        let read = file.read(&mut data[..(left as usize).min(8192)])?;
+        if read == 0 {
+            break;
+        }
+        crc = crc32(crc, &data[..read]);
+        left -= read as u64;
+    }
+    file.seek(SeekFrom::Start(kept))?;
+    Ok((file, part, next, crc))
+}
+
+/// `dir/name`, or `dir/name (n)` if a file already has this name.
+fn free_path(dir: &Path, name: &str) -> PathBuf {
+    let mut path = dir.join(name);
+    let mut count = 1;
+    while path.exists() {
+        path = dir.join(format!("{} ({})", name, count));
+        count += 1;
+    }
+    path
+}
+
+/// Fill `data` as much as the file allows.
+fn read_chunk(file: &mut File, data: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < data.len() {
+        match file.read(&mut data[size..])? {
+            0 => break,
+            read => size += read,
+        }
+    }
+    Ok(size)
+}
+
+/// Update a crc32 (IEEE) with `data`, starting from 0.
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Size for people, like `1.5 KiB`.
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", size)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_ieee() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        // Updated piece by piece, as chunks arrive
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn chunks_cover_the_file() {
+        let len = CHUNK_LEN as u64;
+        assert_eq!(chunk_count(0), 0);
+        assert_eq!(chunk_count(1), 1);
+        assert_eq!(chunk_count(len), 1);
+        assert_eq!(chunk_count(len + 1), 2);
+        assert_eq!(chunk_count(3 * len), 3);
+    }
+
+    #[test]
+    fn resume_keeps_whole_chunks() {
+        let dir = std::env::temp_dir().join(format!("moyu-transfer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let data: Vec<u8> = (0..CHUNK_LEN * 3 + 10).map(|byte| byte as u8).collect();
+        let offer = Offer {
+            id: 1,
+            from: String::from("bob"),
+            name: String::from("data.bin"),
+            size: data.len() as u64,
+            crc: crc32(0, &data),
+        };
+
+        // Nothing yet, the transfer starts from the first chunk
+        let (_, part, next, crc) = open_part(&dir, &offer).unwrap();
+        assert_eq!((next, crc), (0, 0));
+
+        // A chunk and a half was written before it stopped, the half is asked for again
+        fs::write(&part, &data[..CHUNK_LEN + CHUNK_LEN / 2]).unwrap();
+        let (mut file, _, next, crc) = open_part(&dir, &offer).unwrap();
+        assert_eq!((next, crc), (1, crc32(0, &data[..CHUNK_LEN])));
+        assert_eq!(file.stream_position().unwrap(), CHUNK_LEN as u64);
+        assert_eq!(fs::metadata(&part).unwrap().len(), CHUNK_LEN as u64);
+
+        // Every chunk is there, the last one short
+        fs::write(&part, &data).unwrap();
+        let (_, _, next, crc) = open_part(&dir, &offer).unwrap();
+        assert_eq!((next, crc), (chunk_count(offer.size), offer.crc));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn indexes_past_the_end_are_ignored() {
+        let dir = std::env::temp_dir().join(format!("moyu-indexes-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data.bin");
+        let data = vec![7u8; CHUNK_LEN + 1];
+        fs::write(&path, &data).unwrap();
+        let offer = Offer {
+            id: 2,
+            from: String::from("bob"),
+            name: String::from("data.bin"),
+            size: data.len() as u64,
+            crc: crc32(0, &data),
+        };
+        let (file, part, next, crc) = open_part(&dir, &offer).unwrap();
+        let mut worker = Worker {
+            bus: Arc::new(EventBus::new()),
+            socket: Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap()),
+            outgoing: vec![(1, path)],
+            offers: Vec::new(),
+            incoming: vec![Incoming {
+                offer,
+                file,
+                part,
+                next,
+                until: 2,
+                crc,
+                last_chunk: Instant::now(),
+                retries: 0,
+            }],
+        };
+
+        for index in [2, u64::MAX / CHUNK_LEN as u64 + 1, u64::MAX] {
+            worker.send_chunks(1, index, "bob");
+            worker.chunk(2, index, &data[..CHUNK_LEN]);
+        }
+        assert_eq!(worker.incoming[0].next, 0);
+        worker.chunk(2, 0, &data[..CHUNK_LEN]);
+        assert_eq!(worker.incoming[0].next, 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn longest_chunk_fits_a_frame() {
+        protocol::set_protocol(String::from("MOYU"));
+        let longest_id = "学".repeat(protocol::ID_MAX_LEN / 3) + "ab";
+        assert_eq!(longest_id.len(), protocol::ID_MAX_LEN);
+        let data = vec![0xa5; CHUNK_LEN];
+        let mut chunk = control(
+            Code::Chunk,
+            &longest_id,
+            vec![
+                u64::MAX.to_be_bytes().to_vec(),
+                u64::MAX.to_be_bytes().to_vec(),
+                data.clone(),
+            ],
+        );
+        chunk.pro_id.id = longest_id.clone();
+
+        let frame = chunk.to_buf();
+        assert_eq!(frame.len(), protocol::FRAME_MAX_LEN);
+        let parsed = protocol::Message::parse(&frame).unwrap();
+        assert_eq!(parsed.pro_id.id, longest_id);
+        assert_eq!(parsed.fields()[0], longest_id.as_bytes());
+        assert_eq!(parsed.fields()[3], data);
+    }
+}
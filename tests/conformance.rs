@@ -0,0 +1,467 @@
+//! Golden frames of every code, as the Readme describes them.
+//!
+//! Every frame is sent by `alice` at the same time, clock and uid, so only the code, content type
+//! and payload differ. A change to these bytes breaks other implementations of the protocol.
+
+use std::convert::TryFrom;
+
+use communication::prelude::*;
+use memo::MemoStatus;
+use protocol::{Message, Payload};
+use server::{Code, NoticeKind, ReplyResult, Style};
+
+const TIME: u64 = 1_700_000_000_000;
+const CLOCK: u64 = 42;
+const UID: u64 = 0x0123_4567_89ab_cdef;
+/// Uid of the message replied to, edited, deleted or reacted to, and of the file transfer
+const TARGET: u64 = 0xdead_beef;
+
+/// Hex of a frame from `alice`, stamped with `TIME`, `CLOCK` and `UID`.
+macro_rules! frame {
+    ($code:literal, $content_type:literal, $payload:literal) => {
+        concat!(
+            // protocol, then the id with its mark and length
+            "4d4f5955",
+            "ff05616c696365",
+            $code,
+            $content_type,
+            // time, clock and uid
+            "0000018bcfe56800",
+            "000000000000002a",
+            "0123456789abcdef",
+            $payload
+        )
+    };
+}
+
+fn setup() {
+    protocol::set_protocol(String::from("MOYU"));
+    protocol::set_id("alice");
+}
+
+fn stamp(mut message: Message) -> Message {
+    message.pro_id = protocol::ProtocolID {
+        protocol: String::from("MOYU"),
+        id: String::from("alice"),
+    };
+    message.time = TIME;
+    message.clock = CLOCK;
+    message.uid = UID;
+    message
+}
+
+fn control(code: Code, fields: Vec<Vec<u8>>) -> Message {
+    stamp(Message::with_payload(code as u8, Payload::Control(fields)))
+}
+
+fn text(code: Code, text: &str) -> Message {
+    stamp(Message::new(code as u8, text))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&hex[index..index + 2], 16).unwrap())
+        .collect()
+}
+
+fn golden() -> Vec<(Code, Message, &'static str)> {
+    let hello = text(Code::Message, "hello");
+    vec![
+        (Code::None, text(Code::None, ""), frame!("00", "00", "")),
+        (
+            Code::Request,
+            stamp(server::join_request("k3y")),
+            frame!("01", "02", "00036b3379"),
+        ),
+        (
+            Code::Reply,
+            control(
+                Code::Reply,
+                vec![
+                    vec![ReplyResult::Accepted as u8],
+                    b"Success join room".to_vec(),
+                    b"owner".to_vec(),
+                    vec![0, 3],
+                ],
+            ),
+            frame!(
+                "02",
+                "02",
+                "000100001153756363657373206a6f696e20726f6f6d00056f776e657200020003"
+            ),
+        ),
+        (
+            Code::Message,
+            hello.clone(),
+            frame!("03", "00", "68656c6c6f"),
+        ),
+        (
+            Code::Exit,
+            stamp(server::exit_request()),
+            frame!("04", "02", ""),
+        ),
+        (
+            Code::Close,
+            control(Code::Close, vec![b"Room closed".to_vec()]),
+            frame!("05", "02", "000b526f6f6d20636c6f736564"),
+        ),
+        (
+            Code::Heartbeat,
+            text(Code::Heartbeat, ""),
+            frame!("06", "00", ""),
+        ),
+        (
+            Code::History,
+            history::wrap(&hello),
+            frame!("07", "02", "000103000100000568656c6c6f"),
+        ),
+        (
+            Code::Memo,
+            stamp(Message::new_to(Code::Memo as u8, "bob", "see me")),
+            frame!("08", "02", "0003626f620006736565206d65"),
+        ),
+        (
+            Code::Receipt,
+            control(
+                Code::Receipt,
+                vec![b"bob".to_vec(), vec![MemoStatus::Queued as u8]],
+            ),
+            frame!("09", "02", "0003626f62000101"),
+        ),
+        (
+            Code::Private,
+            stamp(Message::new_to(Code::Private as u8, "bob", "psst")),
+            frame!("0a", "02", "0003626f62000470737374"),
+        ),
+        (
+            Code::Answer,
+            stamp(Message::new_about(Code::Answer as u8, TARGET, "yes")),
+            frame!("0b", "02", "000800000000deadbeef0003796573"),
+        ),
+        (
+            Code::Edit,
+            stamp(Message::new_about(Code::Edit as u8, TARGET, "fixed")),
+            frame!("0c", "02", "000800000000deadbeef00056669786564"),
+        ),
+        (
+            Code::Delete,
+            stamp(Message::new_about(Code::Delete as u8, TARGET, "")),
+            frame!("0d", "02", "000800000000deadbeef"),
+        ),
+        (
+            Code::React,
+            stamp(Message::new_about(Code::React as u8, TARGET, "👍")),
+            frame!("0e", "02", "000800000000deadbeef0004f09f918d"),
+        ),
+        (
+            Code::Offer,
+            control(
+                Code::Offer,
+                vec![
+                    Vec::new(),
+                    1000u64.to_be_bytes().to_vec(),
+                    transfer::crc32(0, b"123456789").to_be_bytes().to_vec(),
+                    b"notes.txt".to_vec(),
+                ],
+            ),
+            frame!(
+                "0f",
+                "02",
+                "0000000800000000000003e80004cbf4392600096e6f7465732e747874"
+            ),
+        ),
+        (
+            Code::Accept,
+            control(
+                Code::Accept,
+                vec![
+                    b"bob".to_vec(),
+                    TARGET.to_be_bytes().to_vec(),
+                    2u64.to_be_bytes().to_vec(),
+                ],
+            ),
+            frame!(
+                "10",
+                "02",
+                "0003626f62000800000000deadbeef00080000000000000002"
+            ),
+        ),
+        (
+            Code::Chunk,
+            control(
+                Code::Chunk,
+                vec![
+                    b"bob".to_vec(),
+                    TARGET.to_be_bytes().to_vec(),
+                    2u64.to_be_bytes().to_vec(),
+                    b"data".to_vec(),
+                ],
+            ),
+            frame!(
+                "11",
+                "02",
+                "0003626f62000800000000deadbeef00080000000000000002000464617461"
+            ),
+        ),
+        (
+            Code::Done,
+            control(
+                Code::Done,
+                vec![b"alice".to_vec(), TARGET.to_be_bytes().to_vec(), vec![1]],
+            ),
+            frame!("12", "02", "0005616c696365000800000000deadbeef000101"),
+        ),
+        (
+            Code::Notice,
+            control(
+                Code::Notice,
+                vec![vec![NoticeKind::Joined as u8], b"bob".to_vec()],
+            ),
+            frame!("13", "02", "0001000003626f62"),
+        ),
+        (
+            Code::Nick,
+            stamp(server::nick_request("bob")),
+            frame!("14", "02", "0003626f62"),
+        ),
+        (
+            Code::Presence,
+            stamp(server::presence_update(server::Presence::Away, "lunch")),
+            frame!("15", "02", "00010300056c756e6368"),
+        ),
+    ]
+}
+
+#[test]
+fn codes_match_readme() {
+    let readme = [
+        (Code::None, 0),
+        (Code::Request, 1),
+        (Code::Reply, 2),
+        (Code::Message, 3),
+        (Code::Exit, 4),
+        (Code::Close, 5),
+        (Code::Heartbeat, 6),
+        (Code::History, 7),
+        (Code::Memo, 8),
+        (Code::Receipt, 9),
+        (Code::Private, 10),
+        (Code::Answer, 11),
+        (Code::Edit, 12),
+        (Code::Delete, 13),
+        (Code::React, 14),
+        (Code::Offer, 15),
+        (Code::Accept, 16),
+        (Code::Chunk, 17),
+        (Code::Done, 18),
+        (Code::Notice, 19),
+        (Code::Nick, 20),
+        (Code::Presence, 21),
+    ];
+    assert_eq!(readme.len(), Code::ALL.len());
+    for (code, number) in readme.iter() {
+        assert_eq!(*code as u8, *number);
+        assert_eq!(Code::try_from(*number), Ok(*code));
+    }
+}
+
+#[test]
+fn unknown_codes_are_rejected() {
+    for byte in 0..=u8::MAX {
+        match Code::try_from(byte) {
+            Ok(code) => assert_eq!(code as u8, byte),
+            Err(unknown) => {
+                assert_eq!(unknown.0, byte);
+                assert!((byte as usize) >= Code::ALL.len());
+                assert_eq!(unknown.is_extension(), byte >= 0x80);
+            }
+        }
+    }
+}
+
+#[test]
+fn golden_frames() {
+    setup();
+    let golden = golden();
+    assert_eq!(golden.len(), Code::ALL.len());
+    for (code, message, frame) in golden {
+        assert_eq!(message.code, code as u8, "{:?}", code);
+        assert_eq!(hex(&message.to_buf()), frame, "{:?}", code);
+
+        let parsed = Message::parse(&unhex(frame)).unwrap();
+        assert_eq!(parsed.code, code as u8, "{:?}", code);
+        assert_eq!(parsed.payload, message.payload, "{:?}", code);
+        assert_eq!(parsed.pro_id.id, message.pro_id.id, "{:?}", code);
+        assert_eq!(
+            (parsed.time, parsed.clock, parsed.uid),
+            (TIME, CLOCK, UID),
+            "{:?}",
+            code
+        );
+    }
+}
+
+#[test]
+fn bad_frames_are_rejected() {
+    setup();
+    let message = unhex(frame!("03", "00", "68656c6c6f"));
+    let body_start = message.len() - 5;
+    assert!(Message::parse(&message[..body_start - 1]).is_err());
+
+    let mut unknown_content = message.clone();
+    unknown_content[protocol::PROTOCOL_LEN + 2 + "alice".len() + protocol::CODE_LEN] = 9;
+    assert!(Message::parse(&unknown_content).is_err());
+
+    let mut id_too_long = message.clone();
+    id_too_long[protocol::PROTOCOL_LEN + 1] = protocol::ID_MAX_LEN as u8 + 1;
+    assert!(Message::parse(&id_too_long).is_err());
+
+    let mut id_not_utf8 = message.clone();
+    id_not_utf8[protocol::PROTOCOL_LEN + 2] = 0xc3;
+    assert!(Message::parse(&id_not_utf8).is_err());
+
+    let mut not_utf8 = message;
+    not_utf8.push(0xff);
+    assert!(Message::parse(&not_utf8).is_err());
+
+    let cut_field = unhex(frame!("01", "02", "00036b33"));
+    assert!(Message::parse(&cut_field).is_err());
+}
+
+/// Hex of a v1 frame from `alice`: `[protocol;4][id;12][code;1][text]`.
+macro_rules! frame_v1 {
+    ($code:literal, $text:literal) => {
+        concat!("4d4f5955", "616c69636500000000000000", $code, $text)
+    };
+}
+
+#[test]
+fn v1_frames_are_read() {
+    setup();
+    let hello = Message::parse(&unhex(frame_v1!("02", "68656c6c6f"))).unwrap();
+    assert_eq!(hello.pro_id.id, "alice");
+    assert_eq!(Code::try_from(hello.code), Ok(Code::Message));
+    assert_eq!(hello.payload, Payload::Text(String::from("hello")));
+
+    // The key of a v1 request is the field this version reads
+    let request = Message::parse(&unhex(frame_v1!("00", "6b3379"))).unwrap();
+    assert_eq!(Code::try_from(request.code), Ok(Code::Request));
+    assert_eq!(request.fields(), &[b"k3y".to_vec()]);
+    let leave = Message::parse(&unhex(frame_v1!("00", "4578697420726f6f6d"))).unwrap();
+    assert_eq!(Code::try_from(leave.code), Ok(Code::Exit));
+    let exit = Message::parse(&unhex(frame_v1!("03", ""))).unwrap();
+    assert_eq!(Code::try_from(exit.code), Ok(Code::Exit));
+
+    let reply = Message::parse(&unhex(frame_v1!(
+        "01",
+        "53756363657373206a6f696e20726f6f6d"
+    )))
+    .unwrap();
+    assert_eq!(Code::try_from(reply.code), Ok(Code::Reply));
+    assert_eq!(reply.fields()[0], vec![ReplyResult::Accepted as u8]);
+    assert_eq!(reply.field_text(2), "alice");
+    let refused = Message::parse(&unhex(frame_v1!("01", "4572726f72206b6579"))).unwrap();
+    assert_eq!(refused.fields()[0], vec![ReplyResult::WrongKey as u8]);
+
+    assert!(Message::parse(&unhex(frame_v1!("04", ""))).is_err());
+    assert!(Message::parse(&unhex("4d4f5955616c696365")).is_err());
+}
+
+#[test]
+fn ids_are_utf8() {
+    setup();
+    let name = "学得少的形状";
+    let message = Message {
+        pro_id: protocol::ProtocolID {
+            protocol: String::from("MOYU"),
+            id: String::from(name),
+        },
+        ..text(Code::Message, "你好")
+    };
+    let frame = message.to_buf();
+    assert_eq!(frame[protocol::PROTOCOL_LEN], protocol::ID_MARK);
+    assert_eq!(frame[protocol::PROTOCOL_LEN + 1] as usize, name.len());
+    assert_eq!(Message::parse(&frame).unwrap().pro_id.id, name);
+
+    // Cut on a char boundary, never in the middle of one
+    let long = name.repeat(3);
+    let clean = protocol::clean_id(&long);
+    assert!(clean.len() <= protocol::ID_MAX_LEN);
+    assert!(long.starts_with(&clean));
+    assert_eq!(clean.chars().count(), protocol::ID_MAX_LEN / 3);
+
+    assert_eq!(protocol::clean_id("  bob\t\n"), "bob");
+
+    // Received ids are cleaned too
+    let mut dirty = Message::new(Code::Message as u8, "hi").to_buf();
+    let id = b"\x1b[2Jbo\nb ";
+    dirty.splice(
+        protocol::PROTOCOL_LEN + 1..protocol::PROTOCOL_LEN + 2 + "alice".len(),
+        [&[id.len() as u8][..], id].concat(),
+    );
+    assert_eq!(Message::parse(&dirty).unwrap().pro_id.id, "[2Jbob");
+    assert_eq!(protocol::clean_id("a\u{7}b"), "ab");
+
+    let private = Message::new_to(Code::Private as u8, name, "psst");
+    assert_eq!(
+        private.split_target(),
+        (String::from(name), String::from("psst"))
+    );
+}
+
+#[test]
+fn control_fields_round_trip() {
+    setup();
+    let answer = Message::new_about(Code::Answer as u8, TARGET, "yes");
+    assert_eq!(answer.split_about(), Some((TARGET, String::from("yes"))));
+    let delete = Message::new_about(Code::Delete as u8, TARGET, "");
+    assert_eq!(delete.split_about(), Some((TARGET, String::new())));
+    assert_eq!(text(Code::Message, "0000000000000001").split_about(), None);
+
+    // A history frame gives back the code and payload of any chat message
+    for message in [text(Code::Message, "hello"), answer] {
+        let parsed = Message::parse(&history::wrap(&message).to_buf()).unwrap();
+        let unwrapped = history::unwrap(&parsed).unwrap();
+        assert_eq!(unwrapped.code, message.code);
+        assert_eq!(unwrapped.payload, message.payload);
+        assert_eq!(unwrapped.uid, message.uid);
+    }
+    assert!(history::unwrap(&text(Code::History, "\u{3}hello")).is_none());
+}
+
+#[test]
+fn texts_are_read_without_control_chars() {
+    setup();
+    let sneaky = "\u{1b}[2J\u{1b}]0;owned\u{7}hi\r\n\tthere\u{9b}";
+    let message = Message::parse(&text(Code::Message, sneaky).to_buf()).unwrap();
+    assert_eq!(message.text(), "[2J]0;ownedhi\n\tthere");
+    let private = Message::new_to(Code::Private as u8, "bob", sneaky);
+    assert_eq!(private.split_target().1, "[2J]0;ownedhi\n\tthere");
+}
+
+#[test]
+fn actions_are_styled() {
+    setup();
+    let action = stamp(server::chat_message("waves", Style::Action));
+    assert_eq!(
+        hex(&action.to_buf()),
+        frame!("03", "02", "00057761766573000101")
+    );
+    assert_eq!(
+        server::chat_text(&Message::parse(&action.to_buf()).unwrap()),
+        (String::from("waves"), Style::Action)
+    );
+
+    // The text of a plain message is never read as a style, `/me` in it stays text
+    let plain = server::chat_message("/me waves", Style::Plain);
+    assert_eq!(plain.payload, Payload::Text(String::from("/me waves")));
+    assert_eq!(
+        server::chat_text(&plain),
+        (String::from("/me waves"), Style::Plain)
+    );
+}